@@ -0,0 +1,161 @@
+use std::{collections::HashSet, ops::Range};
+
+/// Bases of one read of a pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// 1 or 2
+    pub read: usize,
+    /// 0-based range of bases
+    pub range: Range<usize>,
+}
+
+/// Positions of the cell barcode and the UMI in the technical read of a pair,
+/// written as `1[1-16];1[17-28]` with 1-based inclusive positions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BarcodeGeometry {
+    pub barcode: Segment,
+    pub umi: Segment,
+}
+
+impl BarcodeGeometry {
+    /// The read of a pair holding the barcode and the UMI, 1 or 2.
+    /// The other read is the biological read.
+    pub fn technical_read(&self) -> usize {
+        self.barcode.read
+    }
+
+    /// Returns the barcode and the UMI of `technical`, or `None` if it is too short.
+    pub fn extract<'a>(&self, technical: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+        Some((
+            technical.get(self.barcode.range.clone())?,
+            technical.get(self.umi.range.clone())?,
+        ))
+    }
+}
+
+impl std::str::FromStr for BarcodeGeometry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let error = || {
+            format!(
+                "Invalid barcode geometry {}. Expected the barcode and the UMI as \
+                READ[START-END];READ[START-END], e.g. 1[1-16];1[17-28]",
+                s
+            )
+        };
+
+        let segments = s
+            .split(';')
+            .map(|segment| parse_segment(segment).ok_or_else(error))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (barcode, umi) = match &segments[..] {
+            [barcode, umi] => (barcode.clone(), umi.clone()),
+            _ => return Err(error()),
+        };
+        if barcode.read != umi.read {
+            return Err("The barcode and the UMI must be in the same read".to_owned());
+        }
+
+        Ok(Self { barcode, umi })
+    }
+}
+
+/// Valid cell barcodes
+pub struct Whitelist {
+    barcodes: HashSet<Vec<u8>>,
+}
+
+impl Whitelist {
+    pub fn new<I: IntoIterator<Item = Vec<u8>>>(barcodes: I) -> Self {
+        Self {
+            barcodes: barcodes.into_iter().collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.barcodes.is_empty()
+    }
+
+    /// Returns `barcode` if it is valid, or the only valid barcode at Hamming
+    /// distance 1 from it.
+    pub fn correct(&self, barcode: &[u8]) -> Option<Vec<u8>> {
+        if self.barcodes.contains(barcode) {
+            return Some(barcode.to_owned());
+        }
+
+        let mut corrected = None;
+        let mut candidate = barcode.to_owned();
+        for i in 0..barcode.len() {
+            for &base in b"ACGT" {
+                if base == barcode[i] {
+                    continue;
+                }
+                candidate[i] = base;
+                if self.barcodes.contains(&candidate) {
+                    if corrected.is_some() {
+                        return None;
+                    }
+                    corrected = Some(candidate.clone());
+                }
+            }
+            candidate[i] = barcode[i];
+        }
+        corrected
+    }
+}
+
+fn parse_segment(s: &str) -> Option<Segment> {
+    let (read, range) = s.trim().strip_suffix(']')?.split_once('[')?;
+    let read = read.parse().ok().filter(|read| *read == 1 || *read == 2)?;
+    let (start, end) = range.split_once('-')?;
+    let start: usize = start.parse().ok()?;
+    let end: usize = end.parse().ok()?;
+    if start == 0 || start > end {
+        return None;
+    }
+    Some(Segment {
+        read,
+        range: start - 1..end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry() {
+        let geometry: BarcodeGeometry = "1[1-16];1[17-28]".parse().unwrap();
+        assert_eq!(geometry.technical_read(), 1);
+        assert_eq!(geometry.barcode.range, 0..16);
+        assert_eq!(geometry.umi.range, 16..28);
+
+        let technical = b"AAAACCCCGGGGTTTTACGTACGTACGTTTTTT";
+        assert_eq!(
+            geometry.extract(technical),
+            Some((&technical[..16], &technical[16..28]))
+        );
+        assert_eq!(geometry.extract(&technical[..20]), None);
+
+        assert!("1[1-16]".parse::<BarcodeGeometry>().is_err());
+        assert!("1[1-16];2[17-28]".parse::<BarcodeGeometry>().is_err());
+        assert!("3[1-16];3[17-28]".parse::<BarcodeGeometry>().is_err());
+        assert!("1[0-16];1[17-28]".parse::<BarcodeGeometry>().is_err());
+    }
+
+    #[test]
+    fn whitelist_correction() {
+        let whitelist = Whitelist::new(vec![b"AACC".to_vec(), b"AAGG".to_vec(), b"TTTT".to_vec()]);
+        assert_eq!(whitelist.correct(b"AACC"), Some(b"AACC".to_vec()));
+        assert_eq!(whitelist.correct(b"ANCC"), Some(b"AACC".to_vec()));
+        assert_eq!(whitelist.correct(b"TTAT"), Some(b"TTTT".to_vec()));
+        // AACG is at distance 1 from both AACC and AAGG
+        assert_eq!(whitelist.correct(b"AACG"), None);
+        assert_eq!(whitelist.correct(b"GGGG"), None);
+    }
+}
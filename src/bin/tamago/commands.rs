@@ -1,11 +1,173 @@
+mod back_splices;
+mod count;
+mod fusions;
 mod index;
 mod map;
+mod merge;
+mod reads;
+mod sam;
 mod stats;
+mod subset;
 
+pub use back_splices::BackSplicesCommand;
+pub use count::CountCommand;
+pub use fusions::FusionsCommand;
 pub use index::IndexCommand;
 pub use map::MapCommand;
+pub use merge::MergeCommand;
 pub use stats::StatsCommand;
+pub use subset::SubsetCommand;
+
+use anyhow::anyhow;
+use reads::FragmentReader;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use tamago::{
+    barcode::BarcodeGeometry,
+    index::{Index, ShardedIndex},
+    mapper::{LibraryType, Mapper, StrandEvidence, Strandedness},
+    sequence,
+    trim::Trimmer,
+};
+
+// Fragments read before they are mapped in parallel, in units of `tamago map --chunk`
+const CHUNK_SIZE: usize = 1024 * 1024;
+// Fragments mapped together by `Mapper::map_batch`
+const BATCH_SIZE: usize = 256;
 
 pub trait Command {
     fn run(self) -> anyhow::Result<()>;
 }
+
+/// Loads an index from `paths`, the shards of the index in order.
+fn load_index(paths: &[PathBuf]) -> anyhow::Result<ShardedIndex> {
+    let mut shards = Vec::with_capacity(paths.len());
+    for path in paths {
+        shards.push(map_index(path)?);
+    }
+    let index = ShardedIndex::new(shards);
+    if paths.len() > 1 {
+        eprintln!(
+            "Loaded {} shards with {} sequences",
+            paths.len(),
+            index.num_seqs()
+        );
+    }
+    Ok(index)
+}
+
+/// Maps the first `num_fragments` fragments of `reader` as unstranded and infers
+/// the strandedness from the strands of the best mappings of their reads.
+fn detect_strandedness(
+    mapper: &Mapper,
+    mut reader: FragmentReader,
+    barcode_geometry: Option<&BarcodeGeometry>,
+    trimmer: &Trimmer,
+    num_fragments: usize,
+    library_type: LibraryType,
+) -> anyhow::Result<Strandedness> {
+    eprintln!(
+        "Inferring library type from up to {} fragments",
+        num_fragments
+    );
+
+    let mut evidence = StrandEvidence::default();
+    let mut num_sampled = 0;
+    while num_sampled < num_fragments {
+        let fragment = match reader.read()? {
+            Some(fragment) => fragment,
+            None => break,
+        };
+        let mates: Vec<_> = match barcode_geometry {
+            Some(geometry) => {
+                let (_, read, is_read1) = fragment.split_technical(geometry);
+                vec![(read, is_read1)]
+            }
+            None => std::iter::once((&fragment.read, true))
+                .chain(fragment.mate.iter().map(|mate| (mate, false)))
+                .collect(),
+        };
+        for (read, is_read1) in mates {
+            let trimmed = trimmer.trim(&read.seq, read.qual.as_deref());
+            let result = mapper.map(&sequence::encode(&read.seq[trimmed]));
+            evidence.add(&result, is_read1);
+        }
+        num_sampled += 1;
+    }
+
+    let strandedness = evidence.infer();
+    eprintln!(
+        "Sampled {} fragments: read 1 mapped forward {} times, reverse {} times, \
+        ambiguously {} times. Inferred library type: {}",
+        num_sampled,
+        evidence.forward,
+        evidence.reverse,
+        evidence.ambiguous,
+        LibraryType {
+            strandedness,
+            ..library_type
+        }
+    );
+    Ok(strandedness)
+}
+
+/// Reads sequence names, one per line. A leading '>' is ignored.
+fn read_names(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let mut names = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let name = line.trim().trim_start_matches('>');
+        if !name.is_empty() {
+            names.push(name.as_bytes().to_owned());
+        }
+    }
+    Ok(names)
+}
+
+/// Reads an index into memory, for commands that modify it.
+fn read_index(path: &Path) -> anyhow::Result<Index> {
+    Index::read(BufReader::new(File::open(path)?)).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+/// Memory-maps an index, for commands that only search it.
+fn map_index(path: &Path) -> anyhow::Result<Index> {
+    Index::map_file(path).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+fn write_index(path: &Path, index: &Index) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    index.write(&mut writer)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tamago::index::IndexBuilder;
+
+    #[test]
+    fn index_files_without_header_are_rejected() {
+        let fasta = b">seq0\nACGTTGCATGTCGCATGATGCATGAGAGCT\n";
+        let index = IndexBuilder::new(&fasta[..]).build().unwrap();
+        let path = std::env::temp_dir().join(format!("tamago-index-{}", std::process::id()));
+
+        write_index(&path, &index).unwrap();
+        assert_eq!(read_index(&path).unwrap().num_seqs(), 1);
+        assert_eq!(map_index(&path).unwrap().num_seqs(), 1);
+
+        // Indexes written before the header was added start with the text length
+        let mut writer = File::create(&path).unwrap();
+        bincode::serialize_into(&mut writer, &index).unwrap();
+        drop(writer);
+        for error in [read_index(&path).err(), map_index(&path).err()] {
+            assert!(error
+                .unwrap()
+                .to_string()
+                .contains("Rebuild it with tamago index"));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+use super::{
+    sam::{self, Record},
+    Command,
+};
+use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+/// Aggregates reads supporting back-splice junctions of circular RNAs
+#[derive(StructOpt, Debug)]
+pub struct BackSplicesCommand {
+    /// SAM file written by `tamago map --back-splices`
+    #[structopt(short, long)]
+    input: PathBuf,
+    #[structopt(long, default_value = "1")]
+    min_support: usize,
+}
+
+impl Command for BackSplicesCommand {
+    fn run(self) -> Result<()> {
+        let reader = BufReader::new(File::open(&self.input)?);
+
+        // Junctions are keyed by sequence and the 1-based first and last positions
+        // of the circularized region
+        let mut junctions: BTreeMap<(String, usize, usize), usize> = BTreeMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.starts_with('@') {
+                continue;
+            }
+
+            let record = Record::parse(&line)?;
+            if !record.is_primary() {
+                continue;
+            }
+
+            let (clip, ref_len) = sam::cigar_extent(record.cigar)?;
+            for part in record.supplementary()? {
+                if part.rname != record.rname || part.is_reverse != record.is_reverse() {
+                    continue;
+                }
+                let (part_clip, part_ref_len) = sam::cigar_extent(part.cigar)?;
+
+                // CIGARs follow the reference strand, so the part with fewer leading
+                // clipped bases comes first in the read as aligned
+                let (first, second) = if clip <= part_clip {
+                    ((record.pos, ref_len), (part.pos, part_ref_len))
+                } else {
+                    ((part.pos, part_ref_len), (record.pos, ref_len))
+                };
+                if second.0 + second.1 > first.0 {
+                    continue;
+                }
+
+                *junctions
+                    .entry((record.rname.to_owned(), second.0, first.0 + first.1 - 1))
+                    .or_default() += 1;
+            }
+        }
+
+        let mut junctions: Vec<_> = junctions
+            .into_iter()
+            .filter(|(_, support)| *support >= self.min_support)
+            .collect();
+        junctions.sort_by_key(|(_, support)| std::cmp::Reverse(*support));
+
+        println!("sequence\tstart\tend\treads");
+        for ((name, start, end), support) in junctions {
+            println!("{}\t{}\t{}\t{}", name, start, end, support);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+use super::{
+    detect_strandedness, load_index, read_names,
+    reads::{Fragment, FragmentReader},
+    Command, BATCH_SIZE, CHUNK_SIZE,
+};
+use anyhow::Result;
+use rayon::prelude::*;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+use tamago::{
+    barcode::{BarcodeGeometry, Whitelist},
+    index::{SequenceId, ShardedIndex},
+    mapper::{LibraryType, MapResult, Mapper, MapperBuilder, Preset, Strandedness},
+    sequence,
+    trim::Trimmer,
+};
+
+/// Counts UMIs per cell barcode and sequence of the index in single-cell data
+#[derive(StructOpt, Debug)]
+pub struct CountCommand {
+    /// Index, or all the shards written by `tamago index --shards` in order
+    #[structopt(short, long, required = true)]
+    index: Vec<PathBuf>,
+
+    /// Reads whose names end with _BARCODE_UMI, or the first reads of pairs
+    /// with --barcode-geometry
+    #[structopt(short, long)]
+    reads: PathBuf,
+    /// Second reads of pairs, in the same order as the first reads
+    #[structopt(long, requires = "barcode-geometry")]
+    mates: Option<PathBuf>,
+    /// Positions of the cell barcode and the UMI in the technical read of each pair,
+    /// e.g. 1[1-16];1[17-28]. Only the other read of the pair is mapped
+    #[structopt(long, requires = "mates")]
+    barcode_geometry: Option<BarcodeGeometry>,
+    /// File of valid cell barcodes, one per line. Other barcodes are corrected
+    /// to the only valid barcode at Hamming distance 1, if any, or discarded
+    #[structopt(long)]
+    whitelist: Option<PathBuf>,
+
+    /// Salmon-style library format code such as IU, ISR, SF or A,
+    /// or one of fr-unstranded, fr-firststrand, fr-secondstrand and auto
+    #[structopt(short, long, default_value = "fr-unstranded")]
+    library_type: LibraryType,
+    /// Number of reads mapped to infer the library type with `-l auto`
+    #[structopt(long, default_value = "100000")]
+    auto_sample: usize,
+    #[structopt(short = "x", long, default_value = "sr-transcriptome")]
+    preset: Preset,
+    #[structopt(long)]
+    header_sep: Option<String>,
+
+    /// Directory to write matrix.mtx, barcodes.tsv and features.tsv to
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    #[structopt(short, long, default_value = "1")]
+    threads: usize,
+}
+
+/// What a read contributes to the counts
+enum Assignment {
+    /// UMI of a read mapped to a single sequence
+    Umi {
+        barcode: Vec<u8>,
+        umi: Vec<u8>,
+        seq_id: SequenceId,
+        is_corrected: bool,
+    },
+    NoBarcode,
+    InvalidBarcode,
+    Unassigned,
+}
+
+#[derive(Default)]
+struct Summary {
+    num_processed: usize,
+    num_assigned: usize,
+    num_corrected: usize,
+    num_no_barcode: usize,
+    num_invalid_barcode: usize,
+    num_unassigned: usize,
+}
+
+impl Command for CountCommand {
+    fn run(self) -> Result<()> {
+        eprintln!("{:#?}", self);
+
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build_global()?;
+
+        eprintln!("Loading index");
+        let index = load_index(&self.index)?;
+        let mut builder = MapperBuilder::sharded(&index);
+        builder.preset(self.preset).library_type(self.library_type);
+        if self.library_type.strandedness == Strandedness::Auto {
+            let strandedness = detect_strandedness(
+                &builder.build(),
+                self.fragment_reader()?,
+                self.barcode_geometry.as_ref(),
+                &Trimmer::default(),
+                self.auto_sample,
+                self.library_type,
+            )?;
+            builder.library_type(LibraryType {
+                strandedness,
+                ..self.library_type
+            });
+        }
+        let mapper = builder.build();
+
+        let whitelist = match &self.whitelist {
+            Some(path) => {
+                let whitelist = Whitelist::new(read_names(path)?);
+                eprintln!("Loaded {} valid barcodes", whitelist.len());
+                Some(whitelist)
+            }
+            None => None,
+        };
+
+        eprintln!("Counting");
+
+        let mut umis = HashSet::new();
+        let mut summary = Summary::default();
+
+        let mut reader = self.fragment_reader()?;
+        loop {
+            let chunk = reader.read_chunk(CHUNK_SIZE)?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            let assignments: Vec<_> = chunk
+                .par_chunks(BATCH_SIZE)
+                .map(|batch| self.assign(&mapper, whitelist.as_ref(), batch))
+                .collect();
+            for assignment in assignments.into_iter().flatten() {
+                summary.num_processed += 1;
+                match assignment {
+                    Assignment::Umi {
+                        barcode,
+                        umi,
+                        seq_id,
+                        is_corrected,
+                    } => {
+                        summary.num_assigned += 1;
+                        if is_corrected {
+                            summary.num_corrected += 1;
+                        }
+                        umis.insert((barcode, seq_id, umi));
+                    }
+                    Assignment::NoBarcode => summary.num_no_barcode += 1,
+                    Assignment::InvalidBarcode => summary.num_invalid_barcode += 1,
+                    Assignment::Unassigned => summary.num_unassigned += 1,
+                }
+            }
+        }
+
+        let counts = count_molecules(umis);
+        write_matrix(&self.output, &index, &counts)?;
+        summary.print(counts.len());
+
+        Ok(())
+    }
+}
+
+impl CountCommand {
+    fn fragment_reader(&self) -> Result<FragmentReader> {
+        FragmentReader::from_files(&self.reads, self.mates.as_ref(), self.header_sep.clone())
+    }
+
+    fn assign(
+        &self,
+        mapper: &Mapper,
+        whitelist: Option<&Whitelist>,
+        fragments: &[Fragment],
+    ) -> Vec<Assignment> {
+        let reads: Vec<_> = fragments
+            .iter()
+            .map(|fragment| match &self.barcode_geometry {
+                Some(geometry) => {
+                    let (technical, read, _) = fragment.split_technical(geometry);
+                    (read, geometry.extract(&technical.seq))
+                }
+                None => (&fragment.read, header_barcodes(&fragment.read.name)),
+            })
+            .collect();
+        // The mapped reads are the biological reads of pairs, or single reads
+        let is_read1 = match &self.barcode_geometry {
+            Some(geometry) => geometry.technical_read() != 1,
+            None => true,
+        };
+
+        let encoded: Vec<_> = reads
+            .iter()
+            .map(|(read, _)| sequence::encode(&read.seq))
+            .collect();
+        let queries: Vec<_> = encoded.iter().map(|seq| &seq[..]).collect();
+        let results = mapper.map_mate_batch(&queries, is_read1);
+
+        reads
+            .into_iter()
+            .zip(results)
+            .map(|((_, barcodes), result)| {
+                let (barcode, umi) = match barcodes {
+                    Some((barcode, umi)) if is_valid_seq(umi) => (barcode, umi),
+                    _ => return Assignment::NoBarcode,
+                };
+                let corrected = match whitelist {
+                    Some(whitelist) => whitelist.correct(barcode),
+                    None if is_valid_seq(barcode) => Some(barcode.to_owned()),
+                    None => None,
+                };
+                let corrected = match corrected {
+                    Some(corrected) => corrected,
+                    None => return Assignment::InvalidBarcode,
+                };
+                match single_target(&result) {
+                    Some(seq_id) => Assignment::Umi {
+                        is_corrected: corrected != barcode,
+                        barcode: corrected,
+                        umi: umi.to_owned(),
+                        seq_id,
+                    },
+                    None => Assignment::Unassigned,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Counts the molecules of each cell barcode and sequence, as reads sharing
+/// a cell barcode, a sequence and a UMI are one molecule.
+fn count_molecules(
+    umis: HashSet<(Vec<u8>, SequenceId, Vec<u8>)>,
+) -> BTreeMap<(Vec<u8>, SequenceId), usize> {
+    let mut counts = BTreeMap::new();
+    for (barcode, seq_id, _) in umis {
+        *counts.entry((barcode, seq_id)).or_default() += 1;
+    }
+    counts
+}
+
+/// Writes the counts to `dir` as a features by barcodes matrix in the layout of Cell Ranger.
+fn write_matrix(
+    dir: &Path,
+    index: &ShardedIndex,
+    counts: &BTreeMap<(Vec<u8>, SequenceId), usize>,
+) -> Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let mut barcodes: Vec<&[u8]> = counts.keys().map(|(barcode, _)| &barcode[..]).collect();
+    barcodes.dedup();
+    let mut writer = BufWriter::new(File::create(dir.join("barcodes.tsv"))?);
+    for barcode in &barcodes {
+        writer.write_all(barcode)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    // Auxiliary sequences have no rows, as their reads are counted on the
+    // reference sequences
+    let mut rows = vec![0; index.num_seqs()];
+    let mut num_rows = 0;
+    let mut writer = BufWriter::new(File::create(dir.join("features.tsv"))?);
+    for (i, row) in rows.iter_mut().enumerate() {
+        let seq_id = SequenceId(i);
+        if index.is_auxiliary(seq_id) {
+            continue;
+        }
+        num_rows += 1;
+        *row = num_rows;
+        let name = index.seq_name(seq_id);
+        writer.write_all(name)?;
+        writer.write_all(b"\t")?;
+        writer.write_all(name)?;
+        writer.write_all(b"\tGene Expression\n")?;
+    }
+    writer.flush()?;
+
+    let mut writer = BufWriter::new(File::create(dir.join("matrix.mtx"))?);
+    writeln!(writer, "%%MatrixMarket matrix coordinate integer general")?;
+    writeln!(writer, "{} {} {}", num_rows, barcodes.len(), counts.len())?;
+    let mut column = 0;
+    let mut prev_barcode: Option<&[u8]> = None;
+    for ((barcode, seq_id), count) in counts {
+        if prev_barcode != Some(&barcode[..]) {
+            column += 1;
+            prev_barcode = Some(barcode);
+        }
+        writeln!(writer, "{} {} {}", rows[seq_id.0], column, count)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Returns the barcode and the UMI of a read named as `NAME_BARCODE_UMI`.
+fn header_barcodes(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut fields = name.rsplitn(3, |&c| c == b'_');
+    let umi = fields.next()?;
+    let barcode = fields.next()?;
+    fields.next()?;
+    Some((barcode, umi))
+}
+
+fn is_valid_seq(seq: &[u8]) -> bool {
+    !seq.is_empty()
+        && seq
+            .iter()
+            .all(|base| matches!(base, b'A' | b'C' | b'G' | b'T'))
+}
+
+/// Returns the sequence of the best mappings if they are all on the same sequence.
+fn single_target(result: &MapResult) -> Option<SequenceId> {
+    let best = result.mappings.first()?;
+    let is_unique = result
+        .mappings
+        .iter()
+        .take_while(|mapping| mapping.score == best.score)
+        .all(|mapping| mapping.seq_id == best.seq_id);
+    if is_unique {
+        Some(best.seq_id)
+    } else {
+        None
+    }
+}
+
+impl Summary {
+    fn print(&self, num_entries: usize) {
+        let percentage = |count: usize| count as f64 * 100.0 / self.num_processed as f64;
+
+        eprintln!(
+            "Assigned {} / {} reads ({:.2}%), {} with corrected barcodes",
+            self.num_assigned,
+            self.num_processed,
+            percentage(self.num_assigned),
+            self.num_corrected
+        );
+        eprintln!(
+            "No barcode or invalid UMI: {} ({:.2}%)",
+            self.num_no_barcode,
+            percentage(self.num_no_barcode)
+        );
+        eprintln!(
+            "Invalid barcode: {} ({:.2}%)",
+            self.num_invalid_barcode,
+            percentage(self.num_invalid_barcode)
+        );
+        eprintln!(
+            "Unmapped or multi-mapped: {} ({:.2}%)",
+            self.num_unassigned,
+            percentage(self.num_unassigned)
+        );
+        eprintln!("Wrote {} nonzero counts", num_entries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tamago::index::IndexBuilder;
+
+    #[test]
+    fn barcodes_are_read_from_header() {
+        assert_eq!(
+            header_barcodes(b"read1_ACGT_TTGC"),
+            Some((&b"ACGT"[..], &b"TTGC"[..]))
+        );
+        assert_eq!(
+            header_barcodes(b"SRR1.1_x_ACGT_TTGC"),
+            Some((&b"ACGT"[..], &b"TTGC"[..]))
+        );
+        assert_eq!(header_barcodes(b"ACGT_TTGC"), None);
+        assert_eq!(header_barcodes(b"read1"), None);
+    }
+
+    #[test]
+    fn umis_are_collapsed_into_matrix() {
+        let fasta =
+            b">seq0\nACGTTGCATGTCGCATGATGCATGAGAGCT\n>seq1\nTTGACCGATCGATTACGGCATCGATCGAAC\n";
+        let index = ShardedIndex::new(vec![IndexBuilder::new(&fasta[..]).build().unwrap()]);
+
+        let umis: HashSet<_> = [
+            (b"AAAA", 0, b"ACGT"),
+            (b"AAAA", 0, b"ACGT"),
+            (b"AAAA", 0, b"TTTT"),
+            (b"AAAA", 1, b"ACGT"),
+            (b"CCCC", 1, b"ACGT"),
+        ]
+        .iter()
+        .map(|(barcode, seq_id, umi)| (barcode.to_vec(), SequenceId(*seq_id), umi.to_vec()))
+        .collect();
+        let counts = count_molecules(umis);
+
+        let dir = std::env::temp_dir().join(format!("tamago-count-{}", std::process::id()));
+        write_matrix(&dir, &index, &counts).unwrap();
+        let read = |name| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("barcodes.tsv"), "AAAA\nCCCC\n");
+        assert_eq!(
+            read("features.tsv"),
+            "seq0\tseq0\tGene Expression\nseq1\tseq1\tGene Expression\n"
+        );
+        assert_eq!(
+            read("matrix.mtx"),
+            "%%MatrixMarket matrix coordinate integer general\n2 2 3\n1 1 2\n2 1 1\n2 2 1\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
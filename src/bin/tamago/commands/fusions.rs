@@ -0,0 +1,96 @@
+use super::{
+    sam::{self, Record},
+    Command,
+};
+use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+/// Aggregates split reads and spanning pairs supporting candidate fusions
+#[derive(StructOpt, Debug)]
+pub struct FusionsCommand {
+    /// SAM file written by `tamago map --chimeric`
+    #[structopt(short, long)]
+    input: PathBuf,
+    #[structopt(long, default_value = "1")]
+    min_support: usize,
+}
+
+#[derive(Default)]
+struct Support {
+    split_reads: usize,
+    spanning_pairs: usize,
+}
+
+impl Command for FusionsCommand {
+    fn run(self) -> Result<()> {
+        let reader = BufReader::new(File::open(&self.input)?);
+
+        let mut candidates: BTreeMap<(String, String), Support> = BTreeMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.starts_with('@') {
+                continue;
+            }
+
+            let record = Record::parse(&line)?;
+            if !record.is_primary() {
+                continue;
+            }
+
+            // Split reads are counted once per partner from their primary record
+            for part in record.supplementary()? {
+                candidates
+                    .entry(partner_pair(record.rname, part.rname))
+                    .or_default()
+                    .split_reads += 1;
+            }
+
+            // Spanning pairs are counted from their first mate
+            let rnext = record.rnext;
+            if record.flag & sam::FLAG_PAIRED != 0
+                && record.flag & sam::FLAG_FIRST != 0
+                && record.flag & sam::FLAG_MATE_UNMAPPED == 0
+                && rnext != "="
+                && rnext != "*"
+                && rnext != record.rname
+            {
+                candidates
+                    .entry(partner_pair(record.rname, rnext))
+                    .or_default()
+                    .spanning_pairs += 1;
+            }
+        }
+
+        let mut candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|(_, support)| support.split_reads + support.spanning_pairs >= self.min_support)
+            .collect();
+        candidates.sort_by_key(|(_, support)| {
+            std::cmp::Reverse(support.split_reads + support.spanning_pairs)
+        });
+
+        println!("partner1\tpartner2\tsplit_reads\tspanning_pairs");
+        for ((partner1, partner2), support) in candidates {
+            println!(
+                "{}\t{}\t{}\t{}",
+                partner1, partner2, support.split_reads, support.spanning_pairs
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn partner_pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_owned(), b.to_owned())
+    } else {
+        (b.to_owned(), a.to_owned())
+    }
+}
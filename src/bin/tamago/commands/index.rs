@@ -1,48 +1,164 @@
-use super::Command;
+use super::{read_index, read_names, write_index, Command};
+use anyhow::anyhow;
+use bio::io::fasta;
 use std::{
     fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
+    io::BufReader,
+    path::{Path, PathBuf},
 };
 use structopt::StructOpt;
 use tamago::{
     hash::HashFunc,
     index::{suffix_array::SuffixArrayOptions, IndexBuilder},
+    vcf,
 };
 
 #[derive(StructOpt, Debug)]
 pub struct IndexCommand {
-    #[structopt(short, long)]
-    reference: PathBuf,
+    #[structopt(short, long, required_unless = "append")]
+    reference: Option<PathBuf>,
     #[structopt(short, long)]
     index: PathBuf,
+    /// FASTA file of sequences to append to the existing INDEX, which must have
+    /// fixed-length buckets, instead of building a new index. Its suffix array
+    /// options are kept
+    #[structopt(long, conflicts_with_all = &["reference", "shards"])]
+    append: Option<PathBuf>,
     #[structopt(long)]
     header_sep: Option<String>,
+    /// File of names of decoy sequences in the reference, one per line.
+    /// Reads best matching a decoy are discarded by `tamago map`
+    #[structopt(long)]
+    decoys: Option<PathBuf>,
+    /// VCF file of variants whose alternative alleles are indexed with their flanks,
+    /// so that reads carrying them are found as well as those carrying the
+    /// reference alleles. Only alleles substituting the reference allele are used,
+    /// combined with those of the variants within their flanks
+    #[structopt(long)]
+    vcf: Option<PathBuf>,
+    /// Reference bases indexed on each side of the alternative alleles
+    #[structopt(long, default_value = "100")]
+    variant_flank: usize,
+    /// Splits the reference into at most this many shards of about equal length,
+    /// written to INDEX.0, INDEX.1 and so on, which are indexed one at a time
+    #[structopt(long)]
+    shards: Option<usize>,
     #[structopt(subcommand)]
-    sa_opt: SuffixArrayOpt,
+    sa_opt: Option<SuffixArrayOpt>,
 }
 
 impl Command for IndexCommand {
     fn run(self) -> anyhow::Result<()> {
         eprintln!("{:#?}", self);
 
-        let mut builder = IndexBuilder::from_file(self.reference)?.sa_options(self.sa_opt.into());
+        let fasta = match (&self.append, &self.reference) {
+            (Some(path), _) | (None, Some(path)) => path,
+            (None, None) => unreachable!(),
+        };
+        let mut builder = IndexBuilder::from_file(fasta)?;
+        match self.sa_opt {
+            Some(sa_opt) => builder = builder.sa_options(sa_opt.into()),
+            None if self.append.is_none() => {
+                return Err(anyhow!("A suffix array type is required to build an index"))
+            }
+            None => {}
+        }
         if let Some(value) = self.header_sep {
             builder = builder.header_sep(value);
         }
+        if let Some(path) = &self.decoys {
+            let names = read_names(path)?;
+            eprintln!("Marking {} decoy sequences", names.len());
+            builder = builder.decoys(names);
+        }
+        if let Some(path) = &self.vcf {
+            let variants = vcf::read_variants(BufReader::new(File::open(path)?))?;
+            let num_alleles: usize = variants.iter().map(|v| v.alt_alleles.len()).sum();
+            let num_substitutions: usize = variants.iter().map(|v| v.substitutions().count()).sum();
+            eprintln!(
+                "Adding {} alternative alleles of substitutions, skipping {} other alleles",
+                num_substitutions,
+                num_alleles - num_substitutions
+            );
+            builder = builder.variants(variants, self.variant_flank);
+        }
+
+        if self.append.is_some() {
+            eprintln!("Loading index");
+            let mut index = read_index(&self.index)?;
+
+            eprintln!("Appending");
+            let num_appended = builder.append_to(&mut index)?;
+            eprintln!(
+                "Appended {} sequences, {} in total",
+                num_appended,
+                index.num_seqs()
+            );
+
+            eprintln!("Writing");
+            write_index(&self.index, &index)?;
+            return Ok(());
+        }
 
-        eprintln!("Indexing");
-        let index = builder.build()?;
+        let num_shards = match self.shards {
+            Some(num_shards) => num_shards,
+            None => {
+                eprintln!("Indexing");
+                let index = builder.build()?;
 
-        eprintln!("Writing");
-        let mut writer = BufWriter::new(File::create(&self.index)?);
-        bincode::serialize_into(&mut writer, &index)?;
-        writer.flush()?;
+                eprintln!("Writing");
+                write_index(&self.index, &index)?;
+                return Ok(());
+            }
+        };
+        if num_shards == 0 {
+            return Err(anyhow!("--shards must be at least 1"));
+        }
+
+        let len = reference_len(fasta)?;
+        let mut min_shard_len = len / num_shards;
+        if len % num_shards != 0 {
+            min_shard_len += 1;
+        }
+        let mut paths = Vec::new();
+        let index_path = self.index.clone().into_os_string();
+        builder.build_shards(min_shard_len, |shard| {
+            let mut path = index_path.clone();
+            path.push(format!(".{}", paths.len()));
+            let path = PathBuf::from(path);
+            eprintln!(
+                "Writing shard of {} sequences to {}",
+                shard.num_seqs(),
+                path.display()
+            );
+            write_index(&path, &shard)?;
+            paths.push(path);
+            Ok(())
+        })?;
+
+        eprintln!(
+            "Wrote {} shards. Map against all of them with -i {}",
+            paths.len(),
+            paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
 
         Ok(())
     }
 }
 
+/// Returns the total length of the sequences in a FASTA file.
+fn reference_len(path: &Path) -> anyhow::Result<usize> {
+    let mut len = 0;
+    for record in fasta::Reader::from_file(path)?.records() {
+        len += record?.seq().len();
+    }
+    Ok(len)
+}
+
 #[derive(StructOpt, Debug)]
 enum SuffixArrayOpt {
     FixedLengthBuckets {
@@ -1,44 +1,162 @@
 mod parallel;
+mod sam;
 mod serial;
 
-use super::Command;
-use anyhow::Result;
+use super::{
+    detect_strandedness, load_index,
+    reads::{Fragment, FragmentReader, Read},
+    Command, BATCH_SIZE, CHUNK_SIZE,
+};
+use anyhow::{anyhow, Result};
+use sam::{Pairing, ReadGroup};
 use std::{
+    collections::BTreeMap,
     fs::File,
-    io::{BufReader, Write},
+    io::{self, BufWriter, Write},
+    ops::Range,
     path::PathBuf,
     time::Instant,
 };
 use structopt::StructOpt;
 use tamago::{
-    index::Index,
-    mapper::{LibraryType, Mapper, MapperBuilder},
+    barcode::BarcodeGeometry,
+    hash::HashFunc,
+    index::ShardedIndex,
+    mapper::{
+        align::{AlignmentMode, ScoringScheme},
+        LibraryType, Mapper, MapperBuilder, Outcome, Preset, Seeding, Strandedness,
+    },
     sequence,
+    trim::{self, Trimmer},
 };
 
 #[derive(StructOpt, Debug)]
 pub struct MapCommand {
-    #[structopt(short, long)]
-    index: PathBuf,
+    /// Index, or all the shards written by `tamago index --shards` in order
+    #[structopt(short, long, required = true)]
+    index: Vec<PathBuf>,
 
     #[structopt(short, long)]
     reads: PathBuf,
+    /// Second reads of pairs, in the same order as the first reads
+    #[structopt(long)]
+    mates: Option<PathBuf>,
+    /// Positions of the cell barcode and the UMI in the technical read of each pair,
+    /// e.g. 1[1-16];1[17-28]. Only the other read of the pair is mapped
+    #[structopt(long, requires = "mates")]
+    barcode_geometry: Option<BarcodeGeometry>,
 
+    /// Salmon-style library format code such as IU, ISR, SF or A,
+    /// or one of fr-unstranded, fr-firststrand, fr-secondstrand and auto
     #[structopt(short, long, default_value = "fr-unstranded")]
     library_type: LibraryType,
+    #[structopt(long, default_value = "1000")]
+    max_fragment_len: usize,
+    /// Number of reads mapped to infer the library type with `-l auto`
+    #[structopt(long, default_value = "100000")]
+    auto_sample: usize,
 
-    #[structopt(short = "k", long, default_value = "31")]
-    seed_min_len: usize,
-    #[structopt(short, default_value = "1000")]
-    multiplicity: usize,
-    #[structopt(short, default_value = "1")]
-    sparsity: usize,
+    /// Preset of sr-transcriptome, sr-genome-spliced, long-read and small-rna,
+    /// whose values are overridden by explicit options
+    #[structopt(short = "x", long, default_value = "sr-transcriptome")]
+    preset: Preset,
+
+    #[structopt(long)]
+    seeding: Option<Seeding>,
+    #[structopt(short = "k", long)]
+    seed_min_len: Option<usize>,
+    #[structopt(short)]
+    multiplicity: Option<usize>,
+    #[structopt(short)]
+    sparsity: Option<usize>,
+    #[structopt(short, long)]
+    window: Option<usize>,
+    #[structopt(long)]
+    hash: Option<HashFunc>,
+    #[structopt(long)]
+    mismatches: Option<usize>,
+    #[structopt(long)]
+    seed_core_len: Option<usize>,
+    /// Whether to reseed regions of repetitive seeds with shorter seeds [true|false]
+    #[structopt(long)]
+    reseed: Option<bool>,
+    #[structopt(long)]
+    reseed_min_len: Option<usize>,
+    /// Number of hits randomly sampled from seeds that remain repetitive
+    #[structopt(long)]
+    sample_hits: Option<usize>,
+
+    #[structopt(long)]
+    chain_max_gap: Option<usize>,
+    #[structopt(long)]
+    chain_bandwidth: Option<usize>,
+    #[structopt(long)]
+    max_intron_len: Option<usize>,
+
+    #[structopt(long)]
+    match_score: Option<i32>,
+    #[structopt(long)]
+    mismatch_penalty: Option<i32>,
+    #[structopt(long)]
+    gap_open: Option<i32>,
+    #[structopt(long)]
+    gap_extend: Option<i32>,
+    /// Penalty of aligning an ambiguous base [default: mismatch penalty]
+    #[structopt(long)]
+    n_penalty: Option<i32>,
+    #[structopt(long)]
+    splice_penalty: Option<i32>,
+
+    /// Whether to align reads as noisy long reads, splitting them at Z-drop [true|false]
+    #[structopt(long)]
+    long_reads: Option<bool>,
+    /// Align whole reads
+    #[structopt(long, conflicts_with = "local")]
+    end_to_end: bool,
+    /// Soft-clip read ends that do not align well
+    #[structopt(long)]
+    local: bool,
+
+    /// Report parts of chimeric reads as supplementary alignments
+    #[structopt(long)]
+    chimeric: bool,
+    /// Report parts of back-spliced reads as supplementary alignments
+    #[structopt(long)]
+    back_splices: bool,
+    #[structopt(long)]
+    chimeric_min_len: Option<usize>,
+
+    /// Trim common Illumina and Nextera adapters
+    #[structopt(long)]
+    trim_adapters: bool,
+    /// Trim the given 3' adapter
+    #[structopt(long = "adapter", number_of_values = 1)]
+    adapters: Vec<String>,
+    /// Trim poly-A tails and poly-T heads at least this long
+    #[structopt(long)]
+    trim_poly_a: Option<usize>,
+    /// Trim 3' ends below this Phred quality
+    #[structopt(long)]
+    trim_quality: Option<u8>,
 
     #[structopt(long)]
     header_sep: Option<String>,
 
+    /// Read group ID written to an @RG header line and to the RG tag of every record
+    #[structopt(long, parse(try_from_str = parse_rg_id))]
+    rg_id: Option<String>,
+    /// Other field of the @RG line as TAG:VALUE, such as SM:sample1 or PL:ILLUMINA
+    #[structopt(long, number_of_values = 1, requires = "rg-id", parse(try_from_str = parse_rg_field))]
+    rg: Vec<String>,
+
+    /// Files to write reads that are not mapped to, one per input file.
+    /// Pairs are written when none of their reads is mapped
+    #[structopt(long, min_values = 1, max_values = 2)]
+    unmapped_out: Vec<PathBuf>,
+
     #[structopt(short, long, default_value = "1")]
     threads: usize,
+    /// Number of reads or pairs, in units of 1 Mi, read and mapped in parallel at a time
     #[structopt(short, long, default_value = "1")]
     chunk: usize,
 }
@@ -47,25 +165,56 @@ impl Command for MapCommand {
     fn run(self) -> Result<()> {
         eprintln!("{:#?}", self);
 
+        let num_inputs = if self.mates.is_some() { 2 } else { 1 };
+        if !self.unmapped_out.is_empty() && self.unmapped_out.len() != num_inputs {
+            return Err(anyhow!(
+                "--unmapped-out takes one file per input file, {} in total",
+                num_inputs
+            ));
+        }
+
         eprintln!("Loading index");
-        let index: Index = {
-            let reader = BufReader::new(File::open(&self.index)?);
-            bincode::deserialize_from(reader)?
-        };
+        let index = load_index(&self.index)?;
+        if index.num_decoys() > 0 {
+            eprintln!("Index has {} decoy sequences", index.num_decoys());
+        }
+        if index.num_auxiliary() > 0 {
+            eprintln!("Index has {} alternative alleles", index.num_auxiliary());
+        }
 
-        let mapper = MapperBuilder::new(&index)
-            .library_type(self.library_type)
-            .seed_min_len(self.seed_min_len)
-            .seed_max_hits(self.multiplicity)
-            .sparsity(self.sparsity)
-            .build();
+        let mut builder = self.mapper_builder(&index);
+        let trimmer = self.trimmer();
+
+        if self.library_type.strandedness == Strandedness::Auto {
+            let strandedness = detect_strandedness(
+                &builder.build(),
+                self.fragment_reader()?,
+                self.barcode_geometry.as_ref(),
+                &trimmer,
+                self.auto_sample,
+                self.library_type,
+            )?;
+            builder.library_type(LibraryType {
+                strandedness,
+                ..self.library_type
+            });
+        }
+        let mapper = builder.build();
 
         let start_time = Instant::now();
 
+        let context = Context {
+            index: &index,
+            mapper: &mapper,
+            trimmer: &trimmer,
+            read_group_id: self.rg_id.as_deref(),
+            write_unmapped: !self.unmapped_out.is_empty(),
+            barcode_geometry: self.barcode_geometry.as_ref(),
+        };
         if self.threads > 1 {
-            parallel::main(self, &index, &mapper)?;
+            parallel::main(&self, &context)?;
         } else {
-            serial::main(self, &index, &mapper)?;
+            serial::main(&self, &context)?;
         }
 
         eprintln!("Elapsed(ms):{}", start_time.elapsed().as_millis());
@@ -75,15 +224,442 @@ impl Command for MapCommand {
     }
 }
 
-fn map<'a, W: Write>(
-    _out: W,
-    _index: &Index,
-    mapper: &Mapper<'a>,
-    _qname: &[u8],
-    seq: &[u8],
-) -> Result<bool> {
-    let encoded_seq = sequence::encode(seq);
+impl MapCommand {
+    /// Returns a builder of the mapper with the values of the preset, overridden by
+    /// those of the explicit options.
+    fn mapper_builder<'a>(&self, index: &'a ShardedIndex) -> MapperBuilder<'a> {
+        let mut builder = MapperBuilder::sharded(index);
+        builder
+            .preset(self.preset)
+            .library_type(self.library_type)
+            .max_fragment_len(self.max_fragment_len)
+            .scoring(self.scoring())
+            .detect_chimeras(self.chimeric)
+            .detect_back_splices(self.back_splices);
+        if let Some(value) = self.seeding {
+            builder.seeding(value);
+        }
+        if let Some(value) = self.seed_min_len {
+            builder.seed_min_len(value);
+        }
+        if let Some(value) = self.multiplicity {
+            builder.seed_max_hits(value);
+        }
+        if let Some(value) = self.sparsity {
+            builder.sparsity(value);
+        }
+        if let Some(value) = self.window {
+            builder.minimizer_window(value);
+        }
+        if let Some(value) = self.hash {
+            builder.hash_func(value);
+        }
+        if let Some(value) = self.mismatches {
+            builder.seed_max_mismatches(value);
+        }
+        if let Some(value) = self.seed_core_len {
+            builder.seed_core_len(value);
+        }
+        if let Some(value) = self.reseed {
+            builder.reseed(value);
+        }
+        if let Some(value) = self.reseed_min_len {
+            builder.reseed_min_len(value);
+        }
+        if let Some(value) = self.sample_hits {
+            builder.max_sampled_hits(value);
+        }
+        if let Some(value) = self.chain_max_gap {
+            builder.chain_max_gap(value);
+        }
+        if let Some(value) = self.chain_bandwidth {
+            builder.chain_bandwidth(value);
+        }
+        if let Some(value) = self.max_intron_len {
+            builder.max_intron_len(value);
+        }
+        if self.end_to_end {
+            builder.alignment_mode(AlignmentMode::EndToEnd);
+        } else if self.local {
+            builder.alignment_mode(AlignmentMode::Local);
+        }
+        if let Some(value) = self.long_reads {
+            builder.long_reads(value);
+        }
+        if let Some(value) = self.chimeric_min_len {
+            builder.chimeric_min_len(value);
+        }
+        builder
+    }
+
+    fn read_group(&self) -> Option<ReadGroup<'_>> {
+        self.rg_id.as_ref().map(|id| ReadGroup {
+            id,
+            fields: &self.rg,
+        })
+    }
+
+    fn fragment_reader(&self) -> Result<FragmentReader> {
+        FragmentReader::from_files(&self.reads, self.mates.as_ref(), self.header_sep.clone())
+    }
+
+    fn scoring(&self) -> ScoringScheme {
+        let mut scoring = self.preset.scoring();
+        if let Some(value) = self.match_score {
+            scoring.match_score = value;
+        }
+        if let Some(value) = self.mismatch_penalty {
+            scoring.mismatch_penalty = value;
+        }
+        if let Some(value) = self.gap_open {
+            scoring.gap_open = value;
+        }
+        if let Some(value) = self.gap_extend {
+            scoring.gap_extend = value;
+        }
+        if self.n_penalty.is_some() {
+            scoring.n_penalty = self.n_penalty;
+        }
+        if let Some(value) = self.splice_penalty {
+            scoring.splice_penalty = value;
+        }
+        scoring
+    }
+
+    fn trimmer(&self) -> Trimmer {
+        let mut trimmer = Trimmer::default();
+        if self.trim_adapters {
+            for adapter in trim::COMMON_ADAPTERS {
+                trimmer = trimmer.adapter(adapter);
+            }
+        }
+        for adapter in &self.adapters {
+            trimmer = trimmer.adapter(adapter.as_bytes());
+        }
+        if let Some(value) = self.trim_poly_a {
+            trimmer = trimmer.poly_a_min_len(value);
+        }
+        if let Some(value) = self.trim_quality {
+            trimmer = trimmer.quality_cutoff(value);
+        }
+        trimmer
+    }
+}
+
+fn parse_rg_field(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    if bytes.len() < 3 || bytes[2] != b':' || !bytes[..2].iter().all(u8::is_ascii_alphanumeric) {
+        return Err(format!(
+            "Invalid read group field {}. Expected TAG:VALUE",
+            s
+        ));
+    }
+    if s.starts_with("ID:") {
+        return Err("Use --rg-id to set the read group ID".to_owned());
+    }
+    if s.contains(['\t', '\n', '\r']) {
+        return Err(format!(
+            "Read group field {:?} contains a tab or a newline",
+            s
+        ));
+    }
+    Ok(s.to_owned())
+}
+
+fn parse_rg_id(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(['\t', '\n', '\r']) {
+        return Err(format!(
+            "Invalid read group ID {:?}. It must be non-empty without tabs or newlines",
+            s
+        ));
+    }
+    Ok(s.to_owned())
+}
+
+/// Everything shared by the mapping of all batches
+struct Context<'a> {
+    index: &'a ShardedIndex,
+    mapper: &'a Mapper<'a>,
+    trimmer: &'a Trimmer,
+    read_group_id: Option<&'a str>,
+    barcode_geometry: Option<&'a BarcodeGeometry>,
+    write_unmapped: bool,
+}
+
+impl Context<'_> {
+    /// Returns the encoded bases of `read` left after trimming and their range.
+    fn trim(&self, read: &Read) -> (Vec<u8>, Range<usize>) {
+        let trimmed = self.trimmer.trim(&read.seq, read.qual.as_deref());
+        (sequence::encode(&read.seq[trimmed.clone()]), trimmed)
+    }
+}
+
+/// SAM records and unmapped reads of a batch
+#[derive(Default)]
+struct BatchOutput {
+    sam: Vec<u8>,
+    /// Unmapped reads of each input file
+    unmapped: [Vec<u8>; 2],
+}
+
+/// Destinations of the output of all batches
+struct Output {
+    sam: BufWriter<io::Stdout>,
+    unmapped: Vec<BufWriter<File>>,
+}
+
+impl Output {
+    /// Creates the unmapped read files and writes the SAM header.
+    fn create(config: &MapCommand, index: &ShardedIndex) -> Result<Self> {
+        let mut sam = BufWriter::new(io::stdout());
+        sam::write_header(&mut sam, index, config.read_group().as_ref())?;
+        let unmapped = config
+            .unmapped_out
+            .iter()
+            .map(|path| File::create(path).map(BufWriter::new))
+            .collect::<io::Result<_>>()?;
+        Ok(Self { sam, unmapped })
+    }
+
+    fn write(&mut self, batch: &BatchOutput) -> io::Result<()> {
+        self.sam.write_all(&batch.sam)?;
+        for (writer, reads) in self.unmapped.iter_mut().zip(&batch.unmapped) {
+            writer.write_all(reads)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sam.flush()?;
+        for writer in &mut self.unmapped {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps a batch of reads or pairs and returns the outcome of each read.
+fn map_batch(
+    output: &mut BatchOutput,
+    context: &Context,
+    fragments: &[Fragment],
+) -> Result<Vec<Outcome>> {
+    let mut tags = Vec::new();
+    if let Some(id) = context.read_group_id {
+        write!(tags, "\tRG:Z:{}", id)?;
+    }
+
+    let (outcomes, num_mapped_reads) = if let Some(geometry) = context.barcode_geometry {
+        // Only the biological read of a pair is mapped, tagged with the barcode and UMI
+        let mut reads = Vec::with_capacity(fragments.len());
+        for fragment in fragments {
+            let (technical, read, _) = fragment.split_technical(geometry);
+            let mut read_tags = tags.clone();
+            if let Some((barcode, umi)) = geometry.extract(&technical.seq) {
+                sam::write_barcode_tags(&mut read_tags, barcode, umi)?;
+            }
+            reads.push((read, read_tags));
+        }
+        let is_read1 = geometry.technical_read() != 1;
+        (map_reads(&mut output.sam, context, &reads, is_read1)?, 1)
+    } else if fragments.iter().all(|fragment| fragment.mate.is_none()) {
+        // A reader yields either only single reads or only pairs
+        let reads: Vec<_> = fragments
+            .iter()
+            .map(|fragment| (&fragment.read, tags.clone()))
+            .collect();
+        (map_reads(&mut output.sam, context, &reads, true)?, 1)
+    } else {
+        (map_pairs(&mut output.sam, context, fragments, &tags)?, 2)
+    };
+
+    if context.write_unmapped {
+        for (fragment, outcomes) in fragments.iter().zip(outcomes.chunks(num_mapped_reads)) {
+            if outcomes.contains(&Outcome::Mapped) {
+                continue;
+            }
+            fragment.read.write(&mut output.unmapped[0])?;
+            if let Some(mate) = &fragment.mate {
+                mate.write(&mut output.unmapped[1])?;
+            }
+        }
+    }
+
+    Ok(outcomes)
+}
+
+/// Maps single reads, each given with the extra tags of its records, which are
+/// all read 1 or all read 2 of their pairs.
+fn map_reads<W: Write>(
+    mut out: W,
+    context: &Context,
+    reads: &[(&Read, Vec<u8>)],
+    is_read1: bool,
+) -> Result<Vec<Outcome>> {
+    let trimmed: Vec<_> = reads.iter().map(|(read, _)| context.trim(read)).collect();
+    let queries: Vec<_> = trimmed.iter().map(|(seq, _)| &seq[..]).collect();
+    let results = context.mapper.map_mate_batch(&queries, is_read1);
 
-    let mappings = mapper.map(&encoded_seq);
-    Ok(!mappings.is_empty())
+    let mut outcomes = Vec::with_capacity(reads.len());
+    for (((read, tags), (_, trimmed)), result) in reads.iter().zip(trimmed).zip(results) {
+        sam::write_records(&mut out, context.index, read, trimmed, &result, None, tags)?;
+        outcomes.push(result.outcome);
+    }
+    Ok(outcomes)
+}
+
+fn map_pairs<W: Write>(
+    mut out: W,
+    context: &Context,
+    fragments: &[Fragment],
+    tags: &[u8],
+) -> Result<Vec<Outcome>> {
+    let reads: Vec<_> = fragments
+        .iter()
+        .map(|fragment| context.trim(&fragment.read))
+        .collect();
+    let mates: Vec<_> = fragments
+        .iter()
+        .map(|fragment| context.trim(fragment.mate.as_ref().unwrap()))
+        .collect();
+    let pairs: Vec<_> = reads
+        .iter()
+        .zip(&mates)
+        .map(|((seq, _), (mate_seq, _))| (&seq[..], &mate_seq[..]))
+        .collect();
+    let results = context.mapper.map_pair_batch(&pairs);
+
+    let mut outcomes = Vec::with_capacity(fragments.len() * 2);
+    for (((fragment, (_, trimmed)), (_, mate_trimmed)), result) in
+        fragments.iter().zip(reads).zip(mates).zip(results)
+    {
+        let pairing = Pairing {
+            is_read1: true,
+            is_concordant: result.is_concordant,
+            mate: result.read2.mappings.first(),
+        };
+        sam::write_records(
+            &mut out,
+            context.index,
+            &fragment.read,
+            trimmed,
+            &result.read1,
+            Some(&pairing),
+            tags,
+        )?;
+        let pairing = Pairing {
+            is_read1: false,
+            is_concordant: result.is_concordant,
+            mate: result.read1.mappings.first(),
+        };
+        sam::write_records(
+            &mut out,
+            context.index,
+            fragment.mate.as_ref().unwrap(),
+            mate_trimmed,
+            &result.read2,
+            Some(&pairing),
+            tags,
+        )?;
+        outcomes.extend([result.read1.outcome, result.read2.outcome]);
+    }
+
+    Ok(outcomes)
+}
+
+#[derive(Default)]
+struct Summary {
+    num_processed: usize,
+    outcome_counts: BTreeMap<Outcome, usize>,
+}
+
+impl Summary {
+    fn add(&mut self, outcome: Outcome) {
+        self.num_processed += 1;
+        *self.outcome_counts.entry(outcome).or_insert(0) += 1;
+    }
+
+    fn print(&self) {
+        let percentage = |count: usize| count as f64 * 100.0 / self.num_processed as f64;
+
+        let num_mapped = self
+            .outcome_counts
+            .get(&Outcome::Mapped)
+            .copied()
+            .unwrap_or(0);
+        eprintln!(
+            "Mapped {} / {} reads ({:.2}%)",
+            num_mapped,
+            self.num_processed,
+            percentage(num_mapped)
+        );
+
+        let num_decoys = self
+            .outcome_counts
+            .get(&Outcome::Decoy)
+            .copied()
+            .unwrap_or(0);
+        if num_decoys > 0 {
+            eprintln!(
+                "Discarded {} reads best matching decoys ({:.2}%)",
+                num_decoys,
+                percentage(num_decoys)
+            );
+        }
+
+        eprintln!("Unmapped reasons:");
+        for (outcome, count) in &self.outcome_counts {
+            if *outcome != Outcome::Mapped && *outcome != Outcome::Decoy {
+                eprintln!(
+                    "  {}\t{}\t({:.2}%)",
+                    outcome.as_str(),
+                    count,
+                    percentage(*count)
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tamago::index::IndexBuilder;
+
+    #[test]
+    fn explicit_options_override_preset() {
+        // Pseudorandom genome, and a read of it with a large insertion
+        let mut state = 1u64;
+        let genome: Vec<u8> = (0..3000)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1);
+                b"ACGT"[(state >> 62) as usize]
+            })
+            .collect();
+        let mut fasta = b">seq0\n".to_vec();
+        fasta.extend(&genome);
+        let index = ShardedIndex::new(vec![IndexBuilder::new(&fasta[..]).build().unwrap()]);
+        let mut read = genome[200..900].to_vec();
+        read.extend(sequence::reverse_complement(&genome[2000..2150]));
+        read.extend(&genome[900..1600]);
+        let read = sequence::encode(&read);
+
+        let command = |args: &[&str]| {
+            let args = ["map", "-i", "index", "-r", "reads"].iter().chain(args);
+            MapCommand::from_iter_safe(args).unwrap()
+        };
+        let num_supplementary = |args: &[&str]| {
+            let mapper = command(args).mapper_builder(&index).build();
+            mapper.map(&read).supplementary.len()
+        };
+        assert_eq!(num_supplementary(&["-x", "long-read"]), 1);
+        assert_eq!(
+            num_supplementary(&["-x", "long-read", "--long-reads", "false"]),
+            0
+        );
+        let args = ["-x", "sr-genome-spliced", "--reseed", "false"];
+        assert_eq!(command(&args).reseed, Some(false));
+    }
 }
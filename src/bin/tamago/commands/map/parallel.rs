@@ -1,87 +1,57 @@
-use super::MapCommand;
-use anyhow::{anyhow, Result};
-use bio::io::fasta::{self, FastaRead};
+use super::{BatchOutput, Context, MapCommand, Output, Summary};
+use anyhow::Result;
 use rayon::prelude::*;
-use std::{
-    io::{self, BufWriter, Write},
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-    thread,
-};
-use tamago::{index::Index, mapper::Mapper, utils};
+use std::thread;
 
-struct Task {
-    qname: Vec<u8>,
-    seq: Vec<u8>,
-}
-
-pub fn main(config: MapCommand, index: &Index, mapper: &Mapper) -> Result<()> {
+pub fn main(config: &MapCommand, context: &Context) -> Result<()> {
     rayon::ThreadPoolBuilder::new()
         .num_threads(config.threads)
         .build_global()?;
 
-    let chunk_size = config.chunk * 1024 * 1024;
+    let chunk_size = config.chunk * super::CHUNK_SIZE;
 
-    let (writer_tx, writer_rx): (crossbeam_channel::Sender<Vec<u8>>, _) =
+    let mut output = Output::create(config, context.index)?;
+    let (writer_tx, writer_rx): (crossbeam_channel::Sender<BatchOutput>, _) =
         crossbeam_channel::unbounded();
     let writer_thread = thread::spawn(move || -> Result<()> {
-        let out = io::stdout();
-        let mut writer = BufWriter::new(out.lock());
-        for x in writer_rx.into_iter() {
-            writer.write_all(&x)?;
+        for batch_output in writer_rx.into_iter() {
+            output.write(&batch_output)?;
         }
+        output.flush()?;
         Ok(())
     });
 
-    let mut num_processed = 0;
-    let num_mapped = Arc::new(AtomicUsize::new(0));
+    let mut summary = Summary::default();
 
     eprintln!("Starting mapping");
 
-    let mut reader = fasta::Reader::from_file(config.reads)?;
-    let mut record = fasta::Record::new();
-    reader.read(&mut record)?;
-
-    while !record.is_empty() {
-        let mut chunk = Vec::new();
-
-        while !record.is_empty() && chunk.len() < chunk_size {
-            record.check().map_err(|e| anyhow!(e.to_owned()))?;
-            chunk.push(Task {
-                qname: utils::extract_name_bytes(record.id(), &config.header_sep).to_owned(),
-                seq: record.seq().to_owned(),
-            });
-            reader.read(&mut record)?;
+    let mut reader = config.fragment_reader()?;
+    loop {
+        let chunk = reader.read_chunk(chunk_size)?;
+        if chunk.is_empty() {
+            break;
         }
 
-        chunk
-            .par_iter()
-            .try_for_each_with::<_, _, Result<()>>(writer_tx.clone(), |tx, task| {
-                let mut buf = Vec::new();
-                let mapped = super::map(&mut buf, index, mapper, &task.qname, &task.seq)?;
-                if mapped {
-                    num_mapped.fetch_add(1, Ordering::Relaxed);
-                }
-                tx.send(buf)?;
-                Ok(())
-            })?;
-
-        num_processed += chunk.len();
+        let outcomes = chunk
+            .par_chunks(super::BATCH_SIZE)
+            .map_with(writer_tx.clone(), |tx, batch| -> Result<_> {
+                let mut batch_output = BatchOutput::default();
+                let outcomes = super::map_batch(&mut batch_output, context, batch)?;
+                tx.send(batch_output)?;
+                Ok(outcomes)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for outcome in outcomes.into_iter().flatten() {
+            summary.add(outcome);
+        }
     }
 
     eprintln!("Finishing output");
     drop(writer_tx);
     writer_thread.join().unwrap()?;
 
-    let num_mapped = num_mapped.load(std::sync::atomic::Ordering::Relaxed);
-    eprintln!(
-        "Mapped {} / {} reads ({:.2}%)",
-        num_mapped,
-        num_processed,
-        num_mapped as f64 * 100.0 / num_processed as f64
-    );
+    summary.print();
 
     Ok(())
 }
@@ -0,0 +1,303 @@
+use super::Read;
+use std::{
+    io::{self, Write},
+    ops::Range,
+};
+use tamago::{
+    index::{SequenceId, ShardedIndex},
+    mapper::{
+        align::{Cigar, CigarOp},
+        MapResult, Mapping,
+    },
+    sequence,
+};
+
+const FLAG_PAIRED: u16 = 0x1;
+const FLAG_PROPER_PAIR: u16 = 0x2;
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_MATE_UNMAPPED: u16 = 0x8;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_MATE_REVERSE: u16 = 0x20;
+const FLAG_FIRST: u16 = 0x40;
+const FLAG_LAST: u16 = 0x80;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// Read group of all the records
+pub struct ReadGroup<'a> {
+    pub id: &'a str,
+    /// Other fields of the @RG line, as `TAG:VALUE`
+    pub fields: &'a [String],
+}
+
+pub fn write_header<W: Write>(
+    out: &mut W,
+    index: &ShardedIndex,
+    read_group: Option<&ReadGroup>,
+) -> io::Result<()> {
+    writeln!(out, "@HD\tVN:1.6\tSO:unsorted")?;
+    for i in 0..index.num_seqs() {
+        let seq_id = SequenceId(i);
+        if index.is_auxiliary(seq_id) {
+            continue;
+        }
+        out.write_all(b"@SQ\tSN:")?;
+        out.write_all(index.seq_name(seq_id))?;
+        writeln!(out, "\tLN:{}", index.seq(seq_id).len())?;
+    }
+    if let Some(read_group) = read_group {
+        write!(out, "@RG\tID:{}", read_group.id)?;
+        for field in read_group.fields {
+            write!(out, "\t{}", field)?;
+        }
+        writeln!(out)?;
+    }
+    let command_line: Vec<_> = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    writeln!(
+        out,
+        "@PG\tID:{}\tPN:{0}\tVN:{}\tCL:{}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        command_line.join(" ").replace(['\t', '\n', '\r'], " ")
+    )
+}
+
+/// Mate information of a paired read
+pub struct Pairing<'a> {
+    pub is_read1: bool,
+    pub is_concordant: bool,
+    /// First mapping of the mate
+    pub mate: Option<&'a Mapping>,
+}
+
+/// Fields shared by the records of a read
+struct ReadContext<'a> {
+    index: &'a ShardedIndex,
+    read: &'a Read,
+    pairing: Option<&'a Pairing<'a>>,
+    /// Tags written to every record, each preceded by a tab
+    tags: &'a [u8],
+}
+
+pub fn write_records<W: Write>(
+    out: &mut W,
+    index: &ShardedIndex,
+    read: &Read,
+    trimmed: Range<usize>,
+    result: &MapResult,
+    pairing: Option<&Pairing>,
+    tags: &[u8],
+) -> io::Result<()> {
+    let context = ReadContext {
+        index,
+        read,
+        pairing,
+        tags,
+    };
+
+    if result.mappings.is_empty() {
+        let mut flag = FLAG_UNMAPPED;
+        // Unmapped reads are placed at their mate
+        let (rname, pos): (&[u8], _) = match pairing.and_then(|pairing| pairing.mate) {
+            Some(mate) => (index.seq_name(mate.seq_id), mate.pos + 1),
+            None => (b"*", 0),
+        };
+        out.write_all(&read.name)?;
+        if let Some(pairing) = pairing {
+            flag |= pair_flag(pairing);
+        }
+        write!(out, "\t{}\t", flag)?;
+        out.write_all(rname)?;
+        write!(out, "\t{}\t0\t*\t", pos)?;
+        write_mate_fields(out, &context, None)?;
+        out.write_all(b"\t")?;
+        out.write_all(&read.seq)?;
+        out.write_all(b"\t")?;
+        out.write_all(read.qual.as_deref().unwrap_or(b"*"))?;
+        out.write_all(tags)?;
+        write_diagnostics(out, result)?;
+        return out.write_all(b"\n");
+    }
+
+    // The primary alignment and the supplementary alignments of the other parts
+    // of a chimeric read refer to each other through SA tags
+    let parts: Vec<_> = result.mappings[..1]
+        .iter()
+        .chain(&result.supplementary)
+        .collect();
+    let part_cigars: Vec<_> = parts
+        .iter()
+        .map(|mapping| full_cigar(mapping, read.seq.len(), &trimmed))
+        .collect();
+    for (i, mapping) in parts.iter().enumerate() {
+        let mut sa = Vec::new();
+        for (j, other) in parts.iter().enumerate() {
+            if j == i {
+                continue;
+            }
+            sa.extend(index.seq_name(other.seq_id));
+            write!(
+                sa,
+                ",{},{},{},255,{};",
+                other.pos + 1,
+                if other.strand.is_reverse() { '-' } else { '+' },
+                part_cigars[j],
+                other.edit_distance
+            )?;
+        }
+        let flag = if i > 0 { FLAG_SUPPLEMENTARY } else { 0 };
+        write_record(out, &context, mapping, &part_cigars[i], flag, &sa)?;
+        write_diagnostics(out, result)?;
+        out.write_all(b"\n")?;
+    }
+
+    for mapping in &result.mappings[1..] {
+        let cigar = full_cigar(mapping, read.seq.len(), &trimmed);
+        write_record(out, &context, mapping, &cigar, FLAG_SECONDARY, &[])?;
+        write_diagnostics(out, result)?;
+        out.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+/// Returns the CIGAR of `mapping` with trimmed bases reported as soft clips.
+fn full_cigar(mapping: &Mapping, read_len: usize, trimmed: &Range<usize>) -> Cigar {
+    let (mut head_clip, mut tail_clip) = (trimmed.start, read_len - trimmed.end);
+    if mapping.strand.is_reverse() {
+        std::mem::swap(&mut head_clip, &mut tail_clip);
+    }
+    let mut cigar = Cigar::default();
+    cigar.push(CigarOp::SoftClip, head_clip);
+    for &(op, len) in mapping.cigar.ops() {
+        cigar.push(op, len);
+    }
+    cigar.push(CigarOp::SoftClip, tail_clip);
+    cigar
+}
+
+fn pair_flag(pairing: &Pairing) -> u16 {
+    let mut flag = FLAG_PAIRED;
+    flag |= if pairing.is_read1 {
+        FLAG_FIRST
+    } else {
+        FLAG_LAST
+    };
+    if pairing.is_concordant {
+        flag |= FLAG_PROPER_PAIR;
+    }
+    match pairing.mate {
+        Some(mate) if mate.strand.is_reverse() => flag |= FLAG_MATE_REVERSE,
+        Some(_) => {}
+        None => flag |= FLAG_MATE_UNMAPPED,
+    }
+    flag
+}
+
+/// Writes RNEXT, PNEXT and TLEN.
+fn write_mate_fields<W: Write>(
+    out: &mut W,
+    context: &ReadContext,
+    mapping: Option<&Mapping>,
+) -> io::Result<()> {
+    let mate = match context.pairing.and_then(|pairing| pairing.mate) {
+        Some(mate) => mate,
+        None => return out.write_all(b"*\t0\t0"),
+    };
+
+    match mapping {
+        Some(mapping) if mapping.seq_id == mate.seq_id => {
+            let end = mapping.pos + mapping.cigar.ref_len();
+            let mate_end = mate.pos + mate.cigar.ref_len();
+            let len = (end.max(mate_end) - mapping.pos.min(mate.pos)) as i64;
+            let is_leftmost = mapping.pos < mate.pos
+                || (mapping.pos == mate.pos && context.pairing.unwrap().is_read1);
+            let tlen = if is_leftmost { len } else { -len };
+            write!(out, "=\t{}\t{}", mate.pos + 1, tlen)
+        }
+        _ => {
+            out.write_all(context.index.seq_name(mate.seq_id))?;
+            write!(out, "\t{}\t0", mate.pos + 1)
+        }
+    }
+}
+
+fn write_record<W: Write>(
+    out: &mut W,
+    context: &ReadContext,
+    mapping: &Mapping,
+    cigar: &Cigar,
+    mut flag: u16,
+    sa: &[u8],
+) -> io::Result<()> {
+    let read = context.read;
+    if mapping.strand.is_reverse() {
+        flag |= FLAG_REVERSE;
+    }
+    if let Some(pairing) = context.pairing {
+        flag |= pair_flag(pairing);
+    }
+
+    out.write_all(&read.name)?;
+    write!(out, "\t{}\t", flag)?;
+    out.write_all(context.index.seq_name(mapping.seq_id))?;
+    write!(out, "\t{}\t255\t{}\t", mapping.pos + 1, cigar)?;
+    write_mate_fields(out, context, Some(mapping))?;
+    out.write_all(b"\t")?;
+    if flag & FLAG_SECONDARY != 0 {
+        out.write_all(b"*\t*")?;
+    } else if mapping.strand.is_reverse() {
+        let rc_seq = sequence::reverse_complement(&sequence::encode(&read.seq));
+        out.write_all(&sequence::decode(&rc_seq))?;
+        out.write_all(b"\t")?;
+        match &read.qual {
+            Some(qual) => out.write_all(&qual.iter().rev().copied().collect::<Vec<_>>())?,
+            None => out.write_all(b"*")?,
+        }
+    } else {
+        out.write_all(&read.seq)?;
+        out.write_all(b"\t")?;
+        out.write_all(read.qual.as_deref().unwrap_or(b"*"))?;
+    }
+    write!(
+        out,
+        "\tAS:i:{}\tNM:i:{}",
+        mapping.score, mapping.edit_distance
+    )?;
+    if !sa.is_empty() {
+        out.write_all(b"\tSA:Z:")?;
+        out.write_all(sa)?;
+    }
+    out.write_all(context.tags)
+}
+
+/// Writes the raw barcode and UMI as CR and UR tags, and as CB and UB tags
+/// unless they contain uncalled bases.
+pub fn write_barcode_tags<W: Write>(out: &mut W, barcode: &[u8], umi: &[u8]) -> io::Result<()> {
+    for (raw_tag, tag, seq) in [("CR", "CB", barcode), ("UR", "UB", umi)] {
+        write!(out, "\t{}:Z:", raw_tag)?;
+        out.write_all(seq)?;
+        if seq
+            .iter()
+            .all(|base| matches!(base, b'A' | b'C' | b'G' | b'T'))
+        {
+            write!(out, "\t{}:Z:", tag)?;
+            out.write_all(seq)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_diagnostics<W: Write>(out: &mut W, result: &MapResult) -> io::Result<()> {
+    write!(
+        out,
+        "\tZR:Z:{}\tZN:i:{}\tZH:i:{}\tZM:i:{}\tZC:i:{}",
+        result.outcome.as_str(),
+        result.seed_stats.num_tried,
+        result.seed_stats.num_with_hits,
+        result.seed_stats.num_over_cap,
+        result.best_coverage
+    )
+}
@@ -1,44 +1,30 @@
-use super::MapCommand;
-use anyhow::{anyhow, Result};
-use bio::io::fasta::{self, FastaRead};
-use std::io::{self, BufWriter, Write};
-use tamago::{index::Index, mapper::Mapper, utils};
+use super::{BatchOutput, Context, MapCommand, Output, Summary};
+use anyhow::Result;
 
-pub fn main(config: MapCommand, index: &Index, mapper: &Mapper) -> Result<()> {
-    let out = io::stdout();
-    let mut out = BufWriter::new(out.lock());
+pub fn main(config: &MapCommand, context: &Context) -> Result<()> {
+    let mut output = Output::create(config, context.index)?;
 
-    let mut num_processed = 0;
-    let mut num_mapped = 0;
+    let mut summary = Summary::default();
 
     eprintln!("Starting mapping");
 
-    let mut reader = fasta::Reader::from_file(&config.reads)?;
-    let mut record = fasta::Record::new();
-    reader.read(&mut record)?;
-
-    while !record.is_empty() {
-        record.check().map_err(|e| anyhow!(e.to_owned()))?;
-
-        let qname = utils::extract_name_bytes(record.id(), &config.header_sep);
-        let mapped = super::map(&mut out, index, mapper, qname, record.seq())?;
-
-        if mapped {
-            num_mapped += 1
-        };
-        num_processed += 1;
-
-        reader.read(&mut record)?;
+    let mut reader = config.fragment_reader()?;
+    loop {
+        let batch = reader.read_chunk(super::BATCH_SIZE)?;
+        if batch.is_empty() {
+            break;
+        }
+
+        let mut batch_output = BatchOutput::default();
+        for outcome in super::map_batch(&mut batch_output, context, &batch)? {
+            summary.add(outcome);
+        }
+        output.write(&batch_output)?;
     }
 
-    out.flush()?;
+    output.flush()?;
 
-    eprintln!(
-        "Mapped {} / {} reads ({:.2}%)",
-        num_mapped,
-        num_processed,
-        num_mapped as f64 * 100.0 / num_processed as f64
-    );
+    summary.print();
 
     Ok(())
 }
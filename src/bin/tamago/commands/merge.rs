@@ -0,0 +1,38 @@
+use super::{read_index, write_index, Command};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Combines indexes built with the same fixed-length buckets into one,
+/// whose sequences are those of the indexes in order
+#[derive(StructOpt, Debug)]
+pub struct MergeCommand {
+    /// Indexes to merge, which must not share any sequence name
+    #[structopt(short, long, required = true, min_values = 2)]
+    index: Vec<PathBuf>,
+    #[structopt(short, long)]
+    output: PathBuf,
+}
+
+impl Command for MergeCommand {
+    fn run(self) -> anyhow::Result<()> {
+        eprintln!("{:#?}", self);
+
+        eprintln!("Loading {}", self.index[0].display());
+        let mut merged = read_index(&self.index[0])?;
+        for path in &self.index[1..] {
+            eprintln!("Merging {}", path.display());
+            let index = read_index(path)?;
+            merged.merge(&index)?;
+        }
+        eprintln!(
+            "Merged {} indexes with {} sequences",
+            self.index.len(),
+            merged.num_seqs()
+        );
+
+        eprintln!("Writing");
+        write_index(&self.output, &merged)?;
+
+        Ok(())
+    }
+}
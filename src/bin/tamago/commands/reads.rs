@@ -0,0 +1,193 @@
+use anyhow::{anyhow, Result};
+use bio::io::{
+    fasta::{self, FastaRead},
+    fastq::{self, FastqRead},
+};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+use tamago::{barcode::BarcodeGeometry, utils};
+
+pub struct Read {
+    pub name: Vec<u8>,
+    /// Header line as in the input, without the leading `>` or `@`
+    pub header: Vec<u8>,
+    pub seq: Vec<u8>,
+    pub qual: Option<Vec<u8>>,
+}
+
+impl Read {
+    /// Writes the read under its original header, as FASTQ if it has qualities
+    /// and as FASTA otherwise.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match &self.qual {
+            Some(qual) => {
+                out.write_all(b"@")?;
+                out.write_all(&self.header)?;
+                out.write_all(b"\n")?;
+                out.write_all(&self.seq)?;
+                out.write_all(b"\n+\n")?;
+                out.write_all(qual)?;
+            }
+            None => {
+                out.write_all(b">")?;
+                out.write_all(&self.header)?;
+                out.write_all(b"\n")?;
+                out.write_all(&self.seq)?;
+            }
+        }
+        out.write_all(b"\n")
+    }
+}
+
+enum Format {
+    Fasta(fasta::Reader<BufReader<File>>, fasta::Record),
+    Fastq(fastq::Reader<BufReader<File>>, fastq::Record),
+}
+
+/// Reads FASTA or FASTQ records, detecting the format from the first byte.
+pub struct ReadReader {
+    format: Format,
+    header_sep: Option<String>,
+}
+
+impl ReadReader {
+    pub fn from_file<P: AsRef<Path>>(path: P, header_sep: Option<String>) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let format = if reader.fill_buf()?.first() == Some(&b'@') {
+            Format::Fastq(fastq::Reader::new(reader), fastq::Record::new())
+        } else {
+            Format::Fasta(fasta::Reader::new(reader), fasta::Record::new())
+        };
+        Ok(Self { format, header_sep })
+    }
+
+    pub fn read(&mut self) -> Result<Option<Read>> {
+        match &mut self.format {
+            Format::Fasta(reader, record) => {
+                reader.read(record)?;
+                if record.is_empty() {
+                    return Ok(None);
+                }
+                record.check().map_err(|e| anyhow!(e.to_owned()))?;
+                Ok(Some(Read {
+                    name: utils::extract_name_bytes(record.id(), &self.header_sep).to_owned(),
+                    header: header(record.id(), record.desc()),
+                    seq: record.seq().to_owned(),
+                    qual: None,
+                }))
+            }
+            Format::Fastq(reader, record) => {
+                reader.read(record)?;
+                if record.is_empty() {
+                    return Ok(None);
+                }
+                record.check().map_err(|e| anyhow!(e.to_owned()))?;
+                Ok(Some(Read {
+                    name: utils::extract_name_bytes(record.id(), &self.header_sep).to_owned(),
+                    header: header(record.id(), record.desc()),
+                    seq: record.seq().to_owned(),
+                    qual: Some(record.qual().to_owned()),
+                }))
+            }
+        }
+    }
+}
+
+fn header(id: &str, desc: Option<&str>) -> Vec<u8> {
+    let mut header = id.as_bytes().to_owned();
+    if let Some(desc) = desc {
+        header.push(b' ');
+        header.extend(desc.as_bytes());
+    }
+    header
+}
+
+/// A single read, or the two mates of a pair
+pub struct Fragment {
+    pub read: Read,
+    pub mate: Option<Read>,
+}
+
+impl Fragment {
+    /// Returns the technical and the biological reads of a pair, and whether the
+    /// biological read is read 1.
+    pub fn split_technical(&self, geometry: &BarcodeGeometry) -> (&Read, &Read, bool) {
+        let mate = self.mate.as_ref().expect("Barcode geometry requires pairs");
+        if geometry.technical_read() == 1 {
+            (&self.read, mate, false)
+        } else {
+            (mate, &self.read, true)
+        }
+    }
+}
+
+/// Reads single reads, or pairs of mates from two files in the same order.
+pub struct FragmentReader {
+    reads: ReadReader,
+    mates: Option<ReadReader>,
+}
+
+impl FragmentReader {
+    pub fn from_files<P: AsRef<Path>>(
+        reads: P,
+        mates: Option<P>,
+        header_sep: Option<String>,
+    ) -> Result<Self> {
+        Ok(Self {
+            reads: ReadReader::from_file(reads, header_sep.clone())?,
+            mates: mates
+                .map(|mates| ReadReader::from_file(mates, header_sep))
+                .transpose()?,
+        })
+    }
+
+    pub fn read(&mut self) -> Result<Option<Fragment>> {
+        let mates = match &mut self.mates {
+            Some(mates) => mates,
+            None => {
+                return Ok(self.reads.read()?.map(|read| Fragment { read, mate: None }));
+            }
+        };
+
+        match (self.reads.read()?, mates.read()?) {
+            (Some(mut read), Some(mut mate)) => {
+                strip_mate_suffix(&mut read.name, b"/1");
+                strip_mate_suffix(&mut mate.name, b"/2");
+                if read.name != mate.name {
+                    return Err(anyhow!(
+                        "Mates are out of sync: {} and {}",
+                        String::from_utf8_lossy(&read.name),
+                        String::from_utf8_lossy(&mate.name)
+                    ));
+                }
+                Ok(Some(Fragment {
+                    read,
+                    mate: Some(mate),
+                }))
+            }
+            (None, None) => Ok(None),
+            _ => Err(anyhow!("Read files have different numbers of records")),
+        }
+    }
+
+    /// Reads the next `max_len` fragments, or the remaining ones if fewer.
+    pub fn read_chunk(&mut self, max_len: usize) -> Result<Vec<Fragment>> {
+        let mut chunk = Vec::new();
+        while chunk.len() < max_len {
+            match self.read()? {
+                Some(fragment) => chunk.push(fragment),
+                None => break,
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+fn strip_mate_suffix(name: &mut Vec<u8>, suffix: &[u8]) {
+    if name.ends_with(suffix) {
+        name.truncate(name.len() - suffix.len());
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+
+pub const FLAG_PAIRED: u16 = 0x1;
+pub const FLAG_UNMAPPED: u16 = 0x4;
+pub const FLAG_MATE_UNMAPPED: u16 = 0x8;
+pub const FLAG_REVERSE: u16 = 0x10;
+pub const FLAG_FIRST: u16 = 0x40;
+pub const FLAG_SECONDARY: u16 = 0x100;
+pub const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// Fields of a SAM alignment line used by the reports
+pub struct Record<'a> {
+    pub flag: u16,
+    pub rname: &'a str,
+    /// 1-based leftmost position
+    pub pos: usize,
+    pub cigar: &'a str,
+    pub rnext: &'a str,
+    tags: Vec<&'a str>,
+}
+
+impl<'a> Record<'a> {
+    pub fn parse(line: &'a str) -> Result<Self> {
+        let fields: Vec<_> = line.split('\t').collect();
+        if fields.len() < 11 {
+            return Err(anyhow!("Malformed SAM record: {}", line));
+        }
+        Ok(Self {
+            flag: fields[1].parse()?,
+            rname: fields[2],
+            pos: fields[3].parse()?,
+            cigar: fields[5],
+            rnext: fields[6],
+            tags: fields[11..].to_vec(),
+        })
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.flag & (FLAG_UNMAPPED | FLAG_SECONDARY | FLAG_SUPPLEMENTARY) == 0
+    }
+
+    pub fn is_reverse(&self) -> bool {
+        self.flag & FLAG_REVERSE != 0
+    }
+
+    /// Returns the value of tag `name` of type `Z`.
+    pub fn string_tag(&self, name: &str) -> Option<&'a str> {
+        self.tags.iter().find_map(|tag| {
+            tag.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(":Z:"))
+        })
+    }
+
+    /// Returns the alignments listed in the SA tag.
+    pub fn supplementary(&self) -> Result<Vec<Supplementary<'a>>> {
+        match self.string_tag("SA") {
+            Some(sa) => sa
+                .split(';')
+                .filter(|part| !part.is_empty())
+                .map(Supplementary::parse)
+                .collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// An entry of an SA tag
+pub struct Supplementary<'a> {
+    pub rname: &'a str,
+    pub pos: usize,
+    pub is_reverse: bool,
+    pub cigar: &'a str,
+}
+
+impl<'a> Supplementary<'a> {
+    fn parse(part: &'a str) -> Result<Self> {
+        let fields: Vec<_> = part.split(',').collect();
+        if fields.len() < 4 {
+            return Err(anyhow!("Malformed SA entry: {}", part));
+        }
+        Ok(Self {
+            rname: fields[0],
+            pos: fields[1].parse()?,
+            is_reverse: fields[2] == "-",
+            cigar: fields[3],
+        })
+    }
+}
+
+/// Returns the number of leading soft-clipped bases and the number of reference
+/// bases covered by `cigar`.
+pub fn cigar_extent(cigar: &str) -> Result<(usize, usize)> {
+    let (mut leading_clip, mut ref_len) = (0, 0);
+    let mut len = 0;
+    let mut is_leading = true;
+    for c in cigar.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            len = len * 10 + digit as usize;
+            continue;
+        }
+        match c {
+            'S' | 'H' if is_leading => leading_clip += len,
+            'S' | 'H' | 'I' | 'P' => {}
+            'M' | 'D' | 'N' | '=' | 'X' => {
+                ref_len += len;
+                is_leading = false;
+            }
+            _ => return Err(anyhow!("Invalid CIGAR: {}", cigar)),
+        }
+        if c == 'I' {
+            is_leading = false;
+        }
+        len = 0;
+    }
+    Ok((leading_clip, ref_len))
+}
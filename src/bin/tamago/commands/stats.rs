@@ -1,7 +1,6 @@
-use super::Command;
-use std::{fs::File, io::BufReader, path::PathBuf};
+use super::{map_index, Command};
+use std::path::PathBuf;
 use structopt::StructOpt;
-use tamago::index::Index;
 
 #[derive(StructOpt)]
 enum Info {
@@ -21,10 +20,7 @@ pub struct StatsCommand {
 impl Command for StatsCommand {
     #[allow(unused_assignments)]
     fn run(self) -> anyhow::Result<()> {
-        let index: Index = {
-            let reader = BufReader::new(File::open(&self.index)?);
-            bincode::deserialize_from(reader)?
-        };
+        let index = map_index(&self.index)?;
 
         match self.info {
             Info::BucketSizeDistribution => {
@@ -0,0 +1,38 @@
+use super::{read_index, read_names, write_index, Command};
+use std::{collections::HashSet, path::PathBuf};
+use structopt::StructOpt;
+
+/// Writes an index of only the selected sequences of an index with fixed-length buckets
+#[derive(StructOpt, Debug)]
+pub struct SubsetCommand {
+    #[structopt(short, long)]
+    index: PathBuf,
+    /// File of names of the sequences to keep, one per line
+    #[structopt(long)]
+    names: PathBuf,
+    #[structopt(short, long)]
+    output: PathBuf,
+}
+
+impl Command for SubsetCommand {
+    fn run(self) -> anyhow::Result<()> {
+        eprintln!("{:#?}", self);
+
+        let names: HashSet<_> = read_names(&self.names)?.into_iter().collect();
+
+        eprintln!("Loading index");
+        let index = read_index(&self.index)?;
+
+        eprintln!(
+            "Selecting {} of {} sequences",
+            names.len(),
+            index.num_seqs()
+        );
+        let subset = index.subset(&names)?;
+
+        eprintln!("Writing");
+        write_index(&self.output, &subset)?;
+
+        Ok(())
+    }
+}
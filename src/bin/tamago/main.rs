@@ -19,7 +19,12 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 )]
 enum Opt {
     Index(IndexCommand),
-    Map(MapCommand),
+    Map(Box<MapCommand>),
+    Fusions(FusionsCommand),
+    BackSplices(BackSplicesCommand),
+    Count(CountCommand),
+    Merge(MergeCommand),
+    Subset(SubsetCommand),
     Stats(StatsCommand),
 }
 
@@ -27,6 +32,11 @@ fn main() -> anyhow::Result<()> {
     match Opt::from_args() {
         Opt::Index(cmd) => cmd.run(),
         Opt::Map(cmd) => cmd.run(),
+        Opt::Fusions(cmd) => cmd.run(),
+        Opt::BackSplices(cmd) => cmd.run(),
+        Opt::Count(cmd) => cmd.run(),
+        Opt::Merge(cmd) => cmd.run(),
+        Opt::Subset(cmd) => cmd.run(),
         Opt::Stats(cmd) => cmd.run(),
     }
 }
@@ -1,31 +1,68 @@
+pub mod buffer;
 pub mod rank9b;
 pub mod suffix_array;
 
-use crate::{sequence, utils};
+use crate::{sequence, utils, vcf::Variant};
 use bio::io::fasta::{self, FastaRead};
 use bitvec::prelude::*;
+use buffer::Buffer;
+use memmap2::Mmap;
 use rank9b::Rank9b;
 use serde::{Deserialize, Serialize};
-use std::{io, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fs::File,
+    io,
+    path::Path,
+    sync::Arc,
+};
 use suffix_array::{SuffixArray, SuffixArrayOptions};
 
 pub const DELIMITER: u8 = b'$';
 
+// Start of index files
+const MAGIC: &[u8; 8] = b"TAMAGOIX";
+// Version of the index file format, which changes whenever the layout of `Index` does.
+// Version 1 added the decoy flags and the lifts of auxiliary sequences, and version 2
+// moved the text and the suffix array after the other fields so that they can be mapped
+const FORMAT_VERSION: u32 = 2;
+// Written in the byte order of the machine, which the text and the suffix array are in
+const BYTE_ORDER_MARK: u32 = 0x0102_0304;
+// Magic, version, byte order mark and the lengths of the serialized fields,
+// the text and the suffix array
+const HEADER_LEN: usize = 40;
+
 #[derive(Serialize, Deserialize)]
 pub struct Index {
-    #[serde(with = "serde_bytes")]
-    pub seq: Vec<u8>,
+    /// Text of the sequences, which is stored apart from the other fields in index files
+    #[serde(skip)]
+    pub seq: Buffer<u8>,
     pub ends: Vec<usize>,
     pub rank_dict: Rank9b,
     #[serde(with = "serde_bytes")]
     pub name_arena: Vec<u8>,
     pub name_ends: Vec<usize>,
+    /// Whether each sequence is a decoy, which reads are matched against
+    /// but never reported on
+    pub decoys: Vec<bool>,
+    /// Where each auxiliary sequence lies on a reference sequence. Alignments to
+    /// auxiliary sequences are reported on the reference sequences instead
+    pub lifts: Vec<Option<Lift>>,
     pub sa: SuffixArray,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct SequenceId(pub usize);
 
+/// Position of an auxiliary sequence, such as an alternative allele of a variant with
+/// its flanks, on a reference sequence of the same index, base for base
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lift {
+    pub seq_id: SequenceId,
+    pub start: usize,
+}
+
 impl Index {
     pub fn num_seqs(&self) -> usize {
         self.ends.len() - 1
@@ -39,15 +76,299 @@ impl Index {
         &self.seq[self.seq_range(seq_id)]
     }
 
+    pub fn is_decoy(&self, seq_id: SequenceId) -> bool {
+        self.decoys[seq_id.0]
+    }
+
+    pub fn num_decoys(&self) -> usize {
+        self.decoys.iter().filter(|is_decoy| **is_decoy).count()
+    }
+
+    pub fn lift(&self, seq_id: SequenceId) -> Option<Lift> {
+        self.lifts[seq_id.0]
+    }
+
+    pub fn is_auxiliary(&self, seq_id: SequenceId) -> bool {
+        self.lifts[seq_id.0].is_some()
+    }
+
+    pub fn num_auxiliary(&self) -> usize {
+        self.lifts.iter().filter(|lift| lift.is_some()).count()
+    }
+
+    /// Returns the position in the text of the reference base that the base at `pos`
+    /// stands for, which is `pos` itself unless it is on an auxiliary sequence.
+    pub(crate) fn lift_pos(&self, pos: usize) -> usize {
+        let seq_id = self.seq_id_from_pos(pos);
+        match self.lift(seq_id) {
+            Some(lift) => {
+                self.seq_range(lift.seq_id).start + lift.start + pos - self.seq_range(seq_id).start
+            }
+            None => pos,
+        }
+    }
+
+    /// Returns the largest number of sequences that a reference base is on, counting
+    /// its reference sequence and the auxiliary sequences lifted onto it.
+    pub(crate) fn max_copies(&self) -> usize {
+        let mut events = Vec::new();
+        for (i, lift) in self.lifts.iter().enumerate() {
+            if let Some(lift) = lift {
+                let start = self.seq_range(lift.seq_id).start + lift.start;
+                events.push((start, 1));
+                events.push((start + self.seq_range(SequenceId(i)).len(), -1));
+            }
+        }
+        // Sequences ending at a position are removed before those starting there
+        events.sort_unstable();
+
+        let (mut num_copies, mut max_copies) = (1, 1);
+        for (_, change) in events {
+            num_copies += change;
+            max_copies = max_copies.max(num_copies);
+        }
+        max_copies as usize
+    }
+
+    /// Writes the index as a header, the fields other than the text and the suffix
+    /// array serialized with bincode, then the text and the suffix array as laid out
+    /// in memory, each padded to a multiple of 8 bytes so that they can be mapped.
+    pub fn write<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        let fields =
+            bincode::serialize(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let array = buffer::as_bytes(self.sa.array());
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&BYTE_ORDER_MARK.to_ne_bytes())?;
+        for len in [fields.len(), self.seq.len(), self.sa.array().len()] {
+            writer.write_all(&(len as u64).to_le_bytes())?;
+        }
+        for bytes in [&fields[..], &self.seq, array] {
+            writer.write_all(bytes)?;
+            writer.write_all(&[0; 8][..padding(bytes.len())])?;
+        }
+        Ok(())
+    }
+
+    /// Reads an index written by [`Self::write`] into memory.
+    pub fn read<R: io::Read>(mut reader: R) -> io::Result<Index> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => not_an_index(),
+            _ => e,
+        })?;
+        let layout = FileLayout::parse(&header)?;
+
+        let mut fields = vec![0; layout.fields_len + padding(layout.fields_len)];
+        reader.read_exact(&mut fields)?;
+        let mut index: Index = bincode::deserialize(&fields[..layout.fields_len])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut seq = vec![0; layout.seq_len + padding(layout.seq_len)];
+        reader.read_exact(&mut seq)?;
+        seq.truncate(layout.seq_len);
+        let mut array = vec![0u32; layout.array_len];
+        reader.read_exact(buffer::as_bytes_mut(&mut array))?;
+
+        index.seq = seq.into();
+        *index.sa.array_mut() = array.into();
+        Ok(index)
+    }
+
+    /// Memory-maps an index file written by [`Self::write`], reading only the fields
+    /// other than the text and the suffix array into memory. The operating system
+    /// pages in the parts of the text and of the suffix array that are searched,
+    /// so that indexes larger than the memory can be mapped against.
+    ///
+    /// The file must not be modified while the index is in use.
+    pub fn map_file<P: AsRef<Path>>(path: P) -> io::Result<Index> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() < HEADER_LEN as u64 {
+            return Err(not_an_index());
+        }
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        let layout = FileLayout::parse(&map[..HEADER_LEN])?;
+
+        let seq_start = HEADER_LEN + layout.fields_len + padding(layout.fields_len);
+        let array_start = seq_start + layout.seq_len + padding(layout.seq_len);
+        let truncated = || io::Error::new(io::ErrorKind::InvalidData, "Index file is truncated");
+        let fields = map
+            .get(HEADER_LEN..HEADER_LEN + layout.fields_len)
+            .ok_or_else(truncated)?;
+        let mut index: Index = bincode::deserialize(fields)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        index.seq = Buffer::mapped(map.clone(), seq_start, layout.seq_len).ok_or_else(truncated)?;
+        *index.sa.array_mut() =
+            Buffer::mapped(map, array_start, layout.array_len).ok_or_else(truncated)?;
+        Ok(index)
+    }
+
     pub fn size_bytes(&self) -> usize {
         self.seq.len() * std::mem::size_of_val(&self.seq[0])
             + self.ends.len() * std::mem::size_of_val(&self.ends[0])
             + self.rank_dict.size_bytes()
             + self.name_arena.len() * std::mem::size_of_val(&self.name_arena[0])
             + self.name_ends.len() * std::mem::size_of_val(&self.name_ends[0])
+            + self.decoys.len() * std::mem::size_of::<bool>()
+            + self.lifts.len() * std::mem::size_of::<Option<Lift>>()
             + self.sa.size_bytes()
     }
 
+    /// Returns an index of the sequences named `names`, in the order of this index,
+    /// and of their auxiliary sequences, whose suffix array is filtered from this one
+    /// instead of being built again.
+    pub fn subset(&self, names: &HashSet<Vec<u8>>) -> io::Result<Index> {
+        let mut keep: Vec<_> = (0..self.num_seqs())
+            .map(|i| {
+                let seq_id = SequenceId(i);
+                !self.is_auxiliary(seq_id) && names.contains(self.seq_name(seq_id))
+            })
+            .collect();
+        let num_kept = keep.iter().filter(|keep| **keep).count();
+        if num_kept < names.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} names are not found in the index",
+                    names.len() - num_kept
+                ),
+            ));
+        }
+
+        let mut new_ids = vec![None; self.num_seqs()];
+        for i in 0..self.num_seqs() {
+            if let Some(lift) = self.lifts[i] {
+                keep[i] = keep[lift.seq_id.0];
+            }
+        }
+        for (new_id, i) in (0..self.num_seqs()).filter(|&i| keep[i]).enumerate() {
+            new_ids[i] = Some(SequenceId(new_id));
+        }
+
+        let delimiter = self.seq[0];
+        let mut seq = vec![delimiter];
+        let mut ends = vec![1];
+        let mut name_arena = Vec::new();
+        let mut name_ends = vec![0];
+        let mut decoys = Vec::new();
+        let mut lifts = Vec::new();
+        // How far each kept sequence moves towards the start of the text
+        let mut shifts = vec![None; self.num_seqs()];
+        for i in (0..self.num_seqs()).filter(|&i| keep[i]) {
+            let seq_id = SequenceId(i);
+            let range = self.seq_range(seq_id);
+            shifts[i] = Some(range.start - seq.len());
+            seq.extend_from_slice(&self.seq[range]);
+            seq.push(delimiter);
+            ends.push(seq.len());
+
+            name_arena.extend(self.seq_name(seq_id));
+            name_arena.push(b'\n');
+            name_ends.push(name_arena.len());
+            decoys.push(self.decoys[i]);
+            lifts.push(self.lifts[i].map(|lift| Lift {
+                seq_id: new_ids[lift.seq_id.0].unwrap(),
+                start: lift.start,
+            }));
+        }
+
+        let sa = self
+            .sa
+            .filter_map(|pos| {
+                let seq_id = self.seq_id_from_pos(pos as usize);
+                shifts[seq_id.0].map(|shift| pos - shift as u32)
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Only indexes with fixed-length buckets can be subset",
+                )
+            })?;
+
+        Ok(Index {
+            rank_dict: rank_dict(&seq, &ends),
+            seq: seq.into(),
+            ends,
+            name_arena,
+            name_ends,
+            decoys,
+            lifts,
+            sa,
+        })
+    }
+
+    /// Appends the sequences of `other`, which must not share any sequence name
+    /// with this index, and merges its suffix array into this one bucket by bucket.
+    pub fn merge(&mut self, other: &Index) -> io::Result<()> {
+        if !self.sa.is_mergeable_with(&other.sa) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only indexes with fixed-length buckets of the same length can be merged",
+            ));
+        }
+        let names: HashSet<&[u8]> = (0..self.num_seqs())
+            .map(|i| self.seq_name(SequenceId(i)))
+            .collect();
+        for i in 0..other.num_seqs() {
+            let name = other.seq_name(SequenceId(i));
+            if names.contains(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Sequence {} is in both indexes",
+                        String::from_utf8_lossy(name)
+                    ),
+                ));
+            }
+        }
+        check_merged_len(self.seq.len() + other.seq.len() - 1)?;
+
+        let start = self.concat(
+            &other.seq,
+            &other.ends,
+            &other.name_arena,
+            &other.name_ends,
+            &other.decoys,
+            &other.lifts,
+        );
+        self.sa.merge(&other.sa, &self.seq, start - 1);
+        Ok(())
+    }
+
+    /// Appends sequences laid out as in an index, including the leading delimiter
+    /// which this index already ends with, and returns the position of the first
+    /// appended base. The suffix array is left unchanged.
+    fn concat(
+        &mut self,
+        seq: &[u8],
+        ends: &[usize],
+        name_arena: &[u8],
+        name_ends: &[usize],
+        decoys: &[bool],
+        lifts: &[Option<Lift>],
+    ) -> usize {
+        let num_seqs = self.num_seqs();
+        let start = self.seq.len();
+        self.seq.to_mut().extend_from_slice(&seq[1..]);
+        self.ends
+            .extend(ends[1..].iter().map(|end| end + start - 1));
+        let name_start = self.name_arena.len();
+        self.name_arena.extend_from_slice(name_arena);
+        self.name_ends
+            .extend(name_ends[1..].iter().map(|end| end + name_start));
+        self.decoys.extend_from_slice(decoys);
+        self.lifts.extend(lifts.iter().map(|lift| {
+            lift.map(|lift| Lift {
+                seq_id: SequenceId(lift.seq_id.0 + num_seqs),
+                start: lift.start,
+            })
+        }));
+        self.rank_dict = rank_dict(&self.seq, &self.ends);
+        start
+    }
+
     pub(crate) fn seq_id_from_pos(&self, pos: usize) -> SequenceId {
         assert!(self.ends[0] <= pos && pos < self.seq.len(), "Out of bounds");
 
@@ -65,10 +386,95 @@ impl Index {
     }
 }
 
+/// An index split into shards, whose sequences are numbered consecutively
+/// across shards in order
+pub struct ShardedIndex {
+    pub shards: Vec<Index>,
+    /// Global id of the first sequence of each shard
+    first_seq_ids: Vec<usize>,
+}
+
+impl ShardedIndex {
+    pub fn new(shards: Vec<Index>) -> Self {
+        let first_seq_ids = first_seq_ids(&shards);
+        Self {
+            shards,
+            first_seq_ids,
+        }
+    }
+
+    pub fn num_seqs(&self) -> usize {
+        self.shards.iter().map(Index::num_seqs).sum()
+    }
+
+    pub fn seq_name(&self, seq_id: SequenceId) -> &[u8] {
+        let (shard, seq_id) = self.locate(seq_id);
+        self.shards[shard].seq_name(seq_id)
+    }
+
+    pub fn seq(&self, seq_id: SequenceId) -> &[u8] {
+        let (shard, seq_id) = self.locate(seq_id);
+        self.shards[shard].seq(seq_id)
+    }
+
+    pub fn is_decoy(&self, seq_id: SequenceId) -> bool {
+        let (shard, seq_id) = self.locate(seq_id);
+        self.shards[shard].is_decoy(seq_id)
+    }
+
+    pub fn num_decoys(&self) -> usize {
+        self.shards.iter().map(Index::num_decoys).sum()
+    }
+
+    pub fn is_auxiliary(&self, seq_id: SequenceId) -> bool {
+        let (shard, seq_id) = self.locate(seq_id);
+        self.shards[shard].is_auxiliary(seq_id)
+    }
+
+    pub fn num_auxiliary(&self) -> usize {
+        self.shards.iter().map(Index::num_auxiliary).sum()
+    }
+
+    /// Returns the shard holding the sequence `seq_id` and its id in the shard.
+    pub fn locate(&self, seq_id: SequenceId) -> (usize, SequenceId) {
+        locate(&self.first_seq_ids, seq_id)
+    }
+}
+
+impl From<Index> for ShardedIndex {
+    fn from(index: Index) -> Self {
+        Self::new(vec![index])
+    }
+}
+
+pub(crate) fn first_seq_ids<I: std::borrow::Borrow<Index>>(shards: &[I]) -> Vec<usize> {
+    shards
+        .iter()
+        .scan(0, |num_seqs, shard| {
+            let first = *num_seqs;
+            *num_seqs += shard.borrow().num_seqs();
+            Some(first)
+        })
+        .collect()
+}
+
+pub(crate) fn locate(first_seq_ids: &[usize], seq_id: SequenceId) -> (usize, SequenceId) {
+    let shard = first_seq_ids.partition_point(|&first| first <= seq_id.0) - 1;
+    (shard, SequenceId(seq_id.0 - first_seq_ids[shard]))
+}
+
+// Most combinations of the alleles of nearby variants indexed together. Variants
+// beyond it are indexed apart from the preceding ones, even if within their flanks
+const MAX_HAPLOTYPES: usize = 16;
+
 pub struct IndexBuilder<R: io::Read> {
     reader: fasta::Reader<R>,
     sa_options: SuffixArrayOptions,
     header_sep: Option<String>,
+    decoy_names: HashSet<Vec<u8>>,
+    /// Substitutions by sequence name
+    variants: HashMap<Vec<u8>, Vec<Variant>>,
+    variant_flank: usize,
 }
 
 impl IndexBuilder<std::fs::File> {
@@ -83,6 +489,9 @@ impl<R: io::Read> IndexBuilder<R> {
             reader,
             sa_options: SuffixArrayOptions::FixedLengthBuckets { len: 8 },
             header_sep: None,
+            decoy_names: HashSet::new(),
+            variants: HashMap::new(),
+            variant_flank: 0,
         }
     }
 
@@ -100,49 +509,390 @@ impl<R: io::Read> IndexBuilder<R> {
         self
     }
 
-    pub fn build(mut self) -> io::Result<Index> {
-        let mut seq = vec![DELIMITER];
-        let mut ends = vec![1];
-        let mut name_arena = Vec::new();
-        let mut name_ends = vec![0];
+    /// Marks the sequences named `decoy_names` as decoys.
+    pub fn decoys<I: IntoIterator<Item = Vec<u8>>>(mut self, decoy_names: I) -> Self {
+        self.decoy_names.extend(decoy_names);
+        self
+    }
+
+    /// Adds an auxiliary sequence for each alternative allele of `variants`, made of
+    /// the allele and `flank` reference bases on each side, so that reads carrying it
+    /// are seeded as well as those carrying the reference allele. Variants within the
+    /// flanks of each other are added together, as each combination of their alleles
+    /// but the reference one, so that reads carrying several of them are seeded too.
+    ///
+    /// Only alleles substituting a reference allele that matches the reference are
+    /// added, since they keep the positions of the flanks.
+    pub fn variants<I: IntoIterator<Item = Variant>>(mut self, variants: I, flank: usize) -> Self {
+        for mut variant in variants {
+            variant.alt_alleles = variant.substitutions().cloned().collect();
+            if !variant.alt_alleles.is_empty() {
+                self.variants
+                    .entry(variant.chrom.clone())
+                    .or_default()
+                    .push(variant);
+            }
+        }
+        self.variant_flank = flank;
+        self
+    }
+
+    pub fn build(self) -> io::Result<Index> {
+        let mut index = None;
+        self.build_shards(usize::MAX, |shard| {
+            index = Some(shard);
+            Ok(())
+        })?;
+        Ok(index.unwrap())
+    }
+
+    /// Splits the reference into consecutive shards and indexes each, passing the
+    /// shards to `write` in order as they are built. A shard is closed once its
+    /// sequences total at least `min_shard_len` bases.
+    ///
+    /// Returns the number of shards.
+    pub fn build_shards<F>(mut self, min_shard_len: usize, mut write: F) -> io::Result<usize>
+    where
+        F: FnMut(Index) -> io::Result<()>,
+    {
+        let mut num_shards = 0;
+        let mut num_decoys = 0;
+        let mut shard = ShardContents::new();
 
         let mut record = fasta::Record::new();
         self.reader.read(&mut record)?;
 
-        while !record.is_empty() {
+        loop {
+            if record.is_empty() || (shard.num_seqs() > 0 && shard.num_bases() >= min_shard_len) {
+                num_decoys += shard.decoys.iter().filter(|is_decoy| **is_decoy).count();
+                if record.is_empty() && num_decoys < self.decoy_names.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} decoy names are not found in the reference",
+                            self.decoy_names.len() - num_decoys
+                        ),
+                    ));
+                }
+
+                write(shard.build(&self.sa_options))?;
+                num_shards += 1;
+                if record.is_empty() {
+                    break;
+                }
+                shard = ShardContents::new();
+            }
+
             assert!(!record.id().is_empty(), "Expecting id for Fasta record");
+            let name = utils::extract_name_bytes(record.id(), &self.header_sep);
+            shard.push(name, record.seq(), self.decoy_names.contains(name));
+            self.add_variants(&mut shard, name, record.seq());
 
-            seq.extend_from_slice(record.seq());
-            seq.push(DELIMITER);
-            ends.push(seq.len());
+            self.reader.read(&mut record)?;
+        }
 
-            name_arena.extend(utils::extract_name_bytes(record.id(), &self.header_sep));
-            name_arena.push(b'\n');
-            name_ends.push(name_arena.len());
+        Ok(num_shards)
+    }
+
+    /// Appends the sequences of the reference to `index`, merging their suffixes
+    /// into its suffix array instead of building it again. `sa_options` are ignored.
+    ///
+    /// Returns the number of sequences appended.
+    pub fn append_to(mut self, index: &mut Index) -> io::Result<usize> {
+        if !index.sa.is_appendable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only indexes with fixed-length buckets can be appended to",
+            ));
+        }
+
+        let mut names: HashSet<Vec<u8>> = (0..index.num_seqs())
+            .map(|i| index.seq_name(SequenceId(i)).to_owned())
+            .collect();
+        let mut added = ShardContents::new();
+
+        let mut record = fasta::Record::new();
+        self.reader.read(&mut record)?;
+        while !record.is_empty() {
+            assert!(!record.id().is_empty(), "Expecting id for Fasta record");
+            let name = utils::extract_name_bytes(record.id(), &self.header_sep);
+            if !names.insert(name.to_owned()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Sequence {} is already in the index",
+                        String::from_utf8_lossy(name)
+                    ),
+                ));
+            }
+            added.push(name, record.seq(), self.decoy_names.contains(name));
+            self.add_variants(&mut added, name, record.seq());
 
             self.reader.read(&mut record)?;
         }
 
-        sequence::encode_in_place(&mut seq);
+        let num_decoys = added.decoys.iter().filter(|is_decoy| **is_decoy).count();
+        if num_decoys < self.decoy_names.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} decoy names are not found in the appended sequences",
+                    self.decoy_names.len() - num_decoys
+                ),
+            ));
+        }
+        added.push_auxiliary();
+        check_merged_len(index.seq.len() + added.seq.len() - 1)?;
+
+        sequence::encode_in_place(&mut added.seq);
+        let start = index.concat(
+            &added.seq,
+            &added.ends,
+            &added.name_arena,
+            &added.name_ends,
+            &added.decoys,
+            &added.lifts,
+        );
+        index.sa.append(&index.seq, start);
 
-        let mut bvec: BitVec<Lsb0, u64> = BitVec::new();
-        bvec.resize(seq.len(), false);
-        for end in &ends {
-            *bvec.get_mut(end - 1).unwrap() = true;
+        Ok(added.num_seqs() - added.lifts.iter().filter(|lift| lift.is_some()).count())
+    }
+
+    /// Adds the alternative alleles of the variants on `seq`, the last sequence of `shard`.
+    fn add_variants(&self, shard: &mut ShardContents, name: &[u8], seq: &[u8]) {
+        let mut variants: Vec<_> = match self.variants.get(name) {
+            Some(variants) => variants
+                .iter()
+                .filter(|variant| {
+                    let (pos, ref_len) = (variant.pos, variant.ref_allele.len());
+                    matches!(seq.get(pos..pos + ref_len),
+                        Some(bases) if bases.eq_ignore_ascii_case(&variant.ref_allele))
+                })
+                .collect(),
+            None => return,
+        };
+        variants.sort_by_key(|variant| variant.pos);
+
+        // Variants within the flanks of each other, up to `MAX_HAPLOTYPES` combinations
+        let mut groups: Vec<Vec<&Variant>> = Vec::new();
+        let (mut group_end, mut num_haplotypes) = (0, 1);
+        for variant in variants {
+            let num_alleles = variant.alt_alleles.len() + 1;
+            if groups.is_empty()
+                || variant.pos >= group_end + self.variant_flank
+                || num_haplotypes * num_alleles > MAX_HAPLOTYPES
+            {
+                groups.push(Vec::new());
+                group_end = 0;
+                num_haplotypes = 1;
+            }
+            group_end = group_end.max(variant.pos + variant.ref_allele.len());
+            num_haplotypes *= num_alleles;
+            groups.last_mut().unwrap().push(variant);
         }
 
-        let sa = self.sa_options.build(&seq);
-        Ok(Index {
-            seq,
-            ends,
-            rank_dict: Rank9b::from_bit_vec(bvec),
-            name_arena,
-            name_ends,
-            sa,
+        let seq_id = SequenceId(shard.num_seqs() - 1);
+        for group in groups {
+            let end = group
+                .iter()
+                .map(|v| v.pos + v.ref_allele.len())
+                .max()
+                .unwrap();
+            let start = group[0].pos.saturating_sub(self.variant_flank);
+            let end = (end + self.variant_flank).min(seq.len());
+
+            // Allele of each variant, 0 for the reference allele
+            let mut alleles = vec![0; group.len()];
+            while next_haplotype(&mut alleles, &group) {
+                let alts: Vec<_> = group
+                    .iter()
+                    .zip(&alleles)
+                    .filter(|(_, &allele)| allele > 0)
+                    .map(|(variant, &allele)| (variant, &variant.alt_alleles[allele - 1]))
+                    .collect();
+                let overlaps = alts.windows(2).any(|pair| {
+                    let (variant, next) = (pair[0].0, pair[1].0);
+                    variant.pos + variant.ref_allele.len() > next.pos
+                });
+                if overlaps {
+                    continue;
+                }
+
+                let mut haplotype_seq = seq[start..end].to_vec();
+                let mut haplotype_name = name.to_owned();
+                for (variant, alt) in alts {
+                    haplotype_seq[variant.pos - start..][..alt.len()].copy_from_slice(alt);
+                    haplotype_name.extend(format!(":{}:", variant.pos + 1).as_bytes());
+                    haplotype_name.extend(&variant.ref_allele);
+                    haplotype_name.push(b'>');
+                    haplotype_name.extend(alt);
+                }
+
+                shard
+                    .auxiliary
+                    .push((haplotype_name, haplotype_seq, Lift { seq_id, start }));
+            }
+        }
+    }
+}
+
+/// Lengths of the parts of an index file, as given in its header
+struct FileLayout {
+    fields_len: usize,
+    seq_len: usize,
+    array_len: usize,
+}
+
+impl FileLayout {
+    fn parse(header: &[u8]) -> io::Result<Self> {
+        if header[..MAGIC.len()] != MAGIC[..] {
+            return Err(not_an_index());
+        }
+        let word = |i: usize| header[i..i + 4].try_into().unwrap();
+        let version = u32::from_le_bytes(word(8));
+        if version != FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Index of format version {}, but this version of tamago reads version {}. \
+                    Rebuild it with tamago index",
+                    version, FORMAT_VERSION
+                ),
+            ));
+        }
+        if u32::from_ne_bytes(word(12)) != BYTE_ORDER_MARK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Index built on a machine of another byte order. Rebuild it with tamago index",
+            ));
+        }
+
+        let len = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap()) as usize;
+        Ok(Self {
+            fields_len: len(16),
+            seq_len: len(24),
+            array_len: len(32),
         })
     }
 }
 
+fn not_an_index() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Not an index, or built by an older version of tamago. Rebuild it with tamago index",
+    )
+}
+
+/// Returns the number of bytes padding `len` bytes to a multiple of 8.
+fn padding(len: usize) -> usize {
+    (8 - len % 8) % 8
+}
+
+/// Moves `alleles`, one per variant of `group`, to the next combination, returning
+/// `false` once all of them have been gone through.
+fn next_haplotype(alleles: &mut [usize], group: &[&Variant]) -> bool {
+    for (allele, variant) in alleles.iter_mut().zip(group) {
+        if *allele < variant.alt_alleles.len() {
+            *allele += 1;
+            return true;
+        }
+        *allele = 0;
+    }
+    false
+}
+
+fn check_merged_len(len: usize) -> io::Result<()> {
+    if len > u32::MAX as usize + 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The merged index would be too large",
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the dictionary of the ends of the sequences in `seq`.
+fn rank_dict(seq: &[u8], ends: &[usize]) -> Rank9b {
+    let mut bvec: BitVec<Lsb0, u64> = BitVec::new();
+    bvec.resize(seq.len(), false);
+    for end in ends {
+        *bvec.get_mut(end - 1).unwrap() = true;
+    }
+    Rank9b::from_bit_vec(bvec)
+}
+
+/// Sequences and names of an index before it is built
+struct ShardContents {
+    seq: Vec<u8>,
+    ends: Vec<usize>,
+    name_arena: Vec<u8>,
+    name_ends: Vec<usize>,
+    decoys: Vec<bool>,
+    lifts: Vec<Option<Lift>>,
+    /// Auxiliary sequences, which follow all the reference sequences
+    auxiliary: Vec<(Vec<u8>, Vec<u8>, Lift)>,
+}
+
+impl ShardContents {
+    fn new() -> Self {
+        Self {
+            seq: vec![DELIMITER],
+            ends: vec![1],
+            name_arena: Vec::new(),
+            name_ends: vec![0],
+            decoys: Vec::new(),
+            lifts: Vec::new(),
+            auxiliary: Vec::new(),
+        }
+    }
+
+    fn num_seqs(&self) -> usize {
+        self.ends.len() - 1
+    }
+
+    fn num_bases(&self) -> usize {
+        self.seq.len() - self.ends.len()
+    }
+
+    fn push(&mut self, name: &[u8], seq: &[u8], is_decoy: bool) {
+        self.seq.extend_from_slice(seq);
+        self.seq.push(DELIMITER);
+        self.ends.push(self.seq.len());
+
+        self.decoys.push(is_decoy);
+        self.name_arena.extend(name);
+        self.name_arena.push(b'\n');
+        self.name_ends.push(self.name_arena.len());
+        self.lifts.push(None);
+    }
+
+    fn push_auxiliary(&mut self) {
+        for (name, seq, lift) in std::mem::take(&mut self.auxiliary) {
+            self.push(&name, &seq, false);
+            *self.lifts.last_mut().unwrap() = Some(lift);
+        }
+    }
+
+    fn build(mut self, sa_options: &SuffixArrayOptions) -> Index {
+        self.push_auxiliary();
+        let mut seq = self.seq;
+        sequence::encode_in_place(&mut seq);
+
+        let rank_dict = rank_dict(&seq, &self.ends);
+        let sa = sa_options.build(&seq);
+        Index {
+            seq: seq.into(),
+            ends: self.ends,
+            rank_dict,
+            name_arena: self.name_arena,
+            name_ends: self.name_ends,
+            decoys: self.decoys,
+            lifts: self.lifts,
+            sa,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,6 +930,120 @@ mod tests {
         );
     }
 
+    const SEQS: [&[u8]; 3] = [
+        b"ACGTTGCATGTCGCATGATGCATGAGAGCTACGTTGCAGT",
+        b"TTGCATGTCGCATGAACGATCGGATNCGTTGCAGTACGT",
+        b"GCATGTCGCATGATGCATGCCCGTAGCTAGCTTTGCATG",
+    ];
+
+    /// Indexes `SEQS[range]`, naming them by their indices in `SEQS`.
+    fn build_index(range: std::ops::Range<usize>) -> Index {
+        IndexBuilder::new(std::io::Cursor::new(fasta(range)))
+            .sa_options(SuffixArrayOptions::FixedLengthBuckets { len: 4 })
+            .build()
+            .unwrap()
+    }
+
+    fn fasta(range: std::ops::Range<usize>) -> Vec<u8> {
+        let mut fasta = Vec::new();
+        for i in range {
+            fasta.extend(format!(">seq{}\n", i).as_bytes());
+            fasta.extend(SEQS[i]);
+            fasta.push(b'\n');
+        }
+        fasta
+    }
+
+    /// Checks that `index` has the sequences of `expected` and finds the same hits
+    /// for the suffixes of `SEQS`.
+    fn check_same_index(index: &Index, expected: &Index) {
+        assert_eq!(*index.seq, *expected.seq);
+        assert_eq!(index.ends, expected.ends);
+        assert_eq!(index.name_arena, expected.name_arena);
+        for pos in expected.ends[0]..expected.seq.len() - 1 {
+            assert_eq!(index.seq_id_from_pos(pos), expected.seq_id_from_pos(pos));
+        }
+
+        let hits = |index: &Index, query: &[u8]| match index.sa.extension_search(
+            &index.seq,
+            query,
+            4,
+            usize::MAX,
+        ) {
+            suffix_array::Extension::Hits(range, len) => {
+                let mut hits: Vec<_> = range.map(|i| index.sa.index_to_pos(i)).collect();
+                hits.sort_unstable();
+                Some((hits, len))
+            }
+            _ => None,
+        };
+        for seq in &SEQS {
+            let seq = sequence::encode(seq);
+            for start in 0..seq.len() - 4 {
+                assert_eq!(hits(index, &seq[start..]), hits(expected, &seq[start..]));
+            }
+        }
+    }
+
+    #[test]
+    fn written_index_is_read_and_mapped() {
+        let index = build_index(0..SEQS.len());
+        let mut bytes = Vec::new();
+        index.write(&mut bytes).unwrap();
+        check_same_index(&Index::read(&bytes[..]).unwrap(), &index);
+        assert!(Index::read(&bytes[..HEADER_LEN - 1]).is_err());
+
+        let path = std::env::temp_dir().join(format!("tamago-map-{}", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let mapped = Index::map_file(&path).unwrap();
+        check_same_index(&mapped, &index);
+        std::fs::write(&path, &bytes[..bytes.len() - 8]).unwrap();
+        assert!(Index::map_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn append_matches_build() {
+        let mut index = build_index(0..1);
+        let num_appended = IndexBuilder::new(std::io::Cursor::new(fasta(1..3)))
+            .append_to(&mut index)
+            .unwrap();
+        assert_eq!(num_appended, 2);
+        check_same_index(&index, &build_index(0..SEQS.len()));
+
+        let duplicate = IndexBuilder::new(std::io::Cursor::new(fasta(0..1)));
+        assert!(duplicate.append_to(&mut index).is_err());
+    }
+
+    #[test]
+    fn merge_matches_build() {
+        let mut index = build_index(0..1);
+        index.merge(&build_index(1..2)).unwrap();
+        index.merge(&build_index(2..3)).unwrap();
+        check_same_index(&index, &build_index(0..SEQS.len()));
+
+        assert!(index.merge(&build_index(1..2)).is_err());
+        let other = IndexBuilder::new(std::io::Cursor::new(b">other\nACGTACGT\n".to_vec()))
+            .sa_options(SuffixArrayOptions::FixedLengthBuckets { len: 5 })
+            .build()
+            .unwrap();
+        assert!(index.merge(&other).is_err());
+    }
+
+    #[test]
+    fn subset_matches_build() {
+        let index = build_index(0..SEQS.len());
+        let names: HashSet<_> = vec![b"seq1".to_vec(), b"seq2".to_vec()]
+            .into_iter()
+            .collect();
+        check_same_index(&index.subset(&names).unwrap(), &build_index(1..3));
+
+        let names = vec![b"seq1".to_vec(), b"seq3".to_vec()]
+            .into_iter()
+            .collect();
+        assert!(index.subset(&names).is_err());
+    }
+
     #[test]
     #[should_panic(expected = "Out of bounds")]
     fn out_of_bounds_left() {
@@ -0,0 +1,93 @@
+use memmap2::Mmap;
+use std::{marker::PhantomData, mem, ops::Deref, slice, sync::Arc};
+
+/// Plain integers, which any bytes of the right length and alignment are a valid value of
+pub trait Element: Copy {}
+
+impl Element for u8 {}
+impl Element for u32 {}
+
+/// Array of an index, either owned or memory-mapped from an index file, in which case
+/// the operating system pages it in as it is read and may evict it again
+pub struct Buffer<T>(Storage<T>);
+
+enum Storage<T> {
+    Owned(Vec<T>),
+    Mapped {
+        map: Arc<Mmap>,
+        /// Offset in bytes of the first element in `map`
+        offset: usize,
+        len: usize,
+        element: PhantomData<T>,
+    },
+}
+
+impl<T: Element> Buffer<T> {
+    /// Views `len` elements stored at `offset` bytes into `map`, or returns `None`
+    /// if they are out of bounds or misaligned.
+    pub(crate) fn mapped(map: Arc<Mmap>, offset: usize, len: usize) -> Option<Self> {
+        let end = offset.checked_add(len.checked_mul(mem::size_of::<T>())?)?;
+        let is_aligned = (map.as_ptr() as usize + offset) & (mem::align_of::<T>() - 1) == 0;
+        if end > map.len() || !is_aligned {
+            return None;
+        }
+        Some(Self(Storage::Mapped {
+            map,
+            offset,
+            len,
+            element: PhantomData,
+        }))
+    }
+}
+
+impl<T: Clone> Buffer<T> {
+    /// Returns the elements for modification, copying them first if they are mapped.
+    pub fn to_mut(&mut self) -> &mut Vec<T> {
+        if let Storage::Mapped { .. } = self.0 {
+            self.0 = Storage::Owned(self.to_vec());
+        }
+        match &mut self.0 {
+            Storage::Owned(vec) => vec,
+            Storage::Mapped { .. } => unreachable!(),
+        }
+    }
+}
+
+impl<T> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.0 {
+            Storage::Owned(vec) => vec,
+            // Mapped buffers are only created by `Self::mapped`, which checks
+            // that the elements are in bounds and aligned
+            Storage::Mapped {
+                map, offset, len, ..
+            } => unsafe { slice::from_raw_parts(map.as_ptr().add(*offset) as *const T, *len) },
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Buffer<T> {
+    fn from(vec: Vec<T>) -> Self {
+        Self(Storage::Owned(vec))
+    }
+}
+
+impl<T> Default for Buffer<T> {
+    fn default() -> Self {
+        Self(Storage::Owned(Vec::new()))
+    }
+}
+
+/// Returns the bytes of `elements` as laid out in memory.
+pub(crate) fn as_bytes<T: Element>(elements: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(elements.as_ptr() as *const u8, mem::size_of_val(elements)) }
+}
+
+/// Returns the bytes of `elements` as laid out in memory, for reading them in place.
+pub(crate) fn as_bytes_mut<T: Element>(elements: &mut [T]) -> &mut [u8] {
+    unsafe {
+        slice::from_raw_parts_mut(elements.as_mut_ptr() as *mut u8, mem::size_of_val(elements))
+    }
+}
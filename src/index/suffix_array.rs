@@ -2,6 +2,7 @@ mod fixed_length_buckets;
 mod fringed;
 mod hashing;
 mod sa_hash;
+mod sais;
 mod variable_length_buckets;
 
 use fixed_length_buckets::FixedLengthBuckets;
@@ -10,6 +11,7 @@ use hashing::Hashing;
 use sa_hash::SaHash;
 use variable_length_buckets::VariableLengthBuckets;
 
+use super::buffer::Buffer;
 use crate::hash::HashFunc;
 
 use serde::{Deserialize, Serialize};
@@ -61,19 +63,33 @@ impl SuffixArrayOptions {
 trait SuffixArrayVariant {
     fn index_to_pos(&self, index: usize) -> usize;
 
-    fn extension_search(
-        &self,
-        text: &[u8],
-        query: &[u8],
-        min_len: usize,
-        max_hits: usize,
-    ) -> Option<(Range<usize>, usize)>;
+    fn array(&self) -> &[u32];
+
+    fn array_mut(&mut self) -> &mut Buffer<u32>;
+
+    /// Returns the range of suffixes starting with `query[..min_len]`.
+    fn prefix_search(&self, text: &[u8], query: &[u8], min_len: usize) -> Option<Range<usize>>;
+
+    /// Returns the shortest `min_len` that `prefix_search` accepts.
+    fn min_prefix_len(&self) -> usize;
 
     fn bucket_size_distribution(&self) -> BTreeMap<usize, usize>;
 
     fn size_bytes(&self) -> usize;
 }
 
+/// Outcome of [`SuffixArray::extension_search`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extension {
+    /// Range of at most `max_hits` suffixes matching the given number of bases
+    Hits(Range<usize>, usize),
+    /// Range of the suffixes sharing the longest match found, which still has
+    /// more than `max_hits` suffixes because the query was consumed or
+    /// no suffix matched one more base
+    TooManyHits(Range<usize>, usize),
+    NotFound,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum SuffixArray {
     FixedLengthBuckets(FixedLengthBuckets),
@@ -94,22 +110,248 @@ impl SuffixArray {
         }
     }
 
+    /// Whether [`Self::append`] is supported, which it only is with fixed-length buckets
+    pub fn is_appendable(&self) -> bool {
+        matches!(self, Self::FixedLengthBuckets(_))
+    }
+
+    /// Returns the suffix array of the suffixes kept by `f`, which maps their positions
+    /// to those in a new text, or `None` unless it has fixed-length buckets.
+    pub fn filter_map<F: FnMut(u32) -> Option<u32>>(&self, f: F) -> Option<SuffixArray> {
+        match self {
+            Self::FixedLengthBuckets(sa) => Some(Self::FixedLengthBuckets(sa.filter_map(f))),
+            _ => None,
+        }
+    }
+
+    /// Whether [`Self::merge`] can merge `other` into this suffix array, which it can
+    /// if both have fixed-length buckets of the same length
+    pub fn is_mergeable_with(&self, other: &SuffixArray) -> bool {
+        match (self, other) {
+            (Self::FixedLengthBuckets(sa), Self::FixedLengthBuckets(other)) => {
+                sa.bucket_width() == other.bucket_width()
+            }
+            _ => false,
+        }
+    }
+
+    /// Merges `other`, whose positions are shifted by `offset` in `text`, where
+    /// `text` is the text of this suffix array followed by that of `other`
+    /// without its leading delimiter.
+    pub fn merge(&mut self, other: &SuffixArray, text: &[u8], offset: usize) {
+        match (self, other) {
+            (Self::FixedLengthBuckets(sa), Self::FixedLengthBuckets(other)) => {
+                sa.merge(other, text, offset)
+            }
+            _ => panic!("Only suffix arrays with fixed-length buckets can be merged"),
+        }
+    }
+
+    /// Inserts the suffixes of `text` starting at `start` or later, where `text[..start]`
+    /// is the text the suffix array was built from, without sorting the whole text again.
+    pub fn append(&mut self, text: &[u8], start: usize) {
+        match self {
+            Self::FixedLengthBuckets(sa) => sa.append(text, start),
+            _ => panic!("Only suffix arrays with fixed-length buckets can be appended to"),
+        }
+    }
+
+    pub(crate) fn array(&self) -> &[u32] {
+        match self {
+            Self::FixedLengthBuckets(sa) => sa.array(),
+            Self::VariableLengthBuckets(sa) => sa.array(),
+            Self::Hashing(sa) => sa.array(),
+            Self::Fringed(sa) => sa.array(),
+            Self::SaHash(sa) => sa.array(),
+        }
+    }
+
+    /// Returns the array of suffix positions, which is stored apart from the other
+    /// fields in index files.
+    pub(crate) fn array_mut(&mut self) -> &mut Buffer<u32> {
+        match self {
+            Self::FixedLengthBuckets(sa) => sa.array_mut(),
+            Self::VariableLengthBuckets(sa) => sa.array_mut(),
+            Self::Hashing(sa) => sa.array_mut(),
+            Self::Fringed(sa) => sa.array_mut(),
+            Self::SaHash(sa) => sa.array_mut(),
+        }
+    }
+
+    fn prefix_search(&self, text: &[u8], query: &[u8], min_len: usize) -> Option<Range<usize>> {
+        match self {
+            Self::FixedLengthBuckets(sa) => sa.prefix_search(text, query, min_len),
+            Self::VariableLengthBuckets(sa) => sa.prefix_search(text, query, min_len),
+            Self::Hashing(sa) => sa.prefix_search(text, query, min_len),
+            Self::Fringed(sa) => sa.prefix_search(text, query, min_len),
+            Self::SaHash(sa) => sa.prefix_search(text, query, min_len),
+        }
+    }
+
+    /// Returns the shortest prefix of a query that the searches can match,
+    /// which is the length of the prefixes the suffixes are bucketed by.
+    pub fn min_search_len(&self) -> usize {
+        match self {
+            Self::FixedLengthBuckets(sa) => sa.min_prefix_len(),
+            Self::VariableLengthBuckets(sa) => sa.min_prefix_len(),
+            Self::Hashing(sa) => sa.min_prefix_len(),
+            Self::Fringed(sa) => sa.min_prefix_len(),
+            Self::SaHash(sa) => sa.min_prefix_len(),
+        }
+    }
+
+    /// Narrows `range`, whose suffixes share `query[..depth]`, to those also matching `query[depth]`.
+    pub(crate) fn narrow(
+        &self,
+        text: &[u8],
+        query: &[u8],
+        depth: usize,
+        range: Range<usize>,
+    ) -> Range<usize> {
+        let mut begin = range.start;
+        let mut end = range.end;
+        unsafe {
+            equal_range(
+                self.array(),
+                text.as_ptr().add(depth),
+                query.as_ptr().add(depth),
+                query.as_ptr().add(depth + 1),
+                &mut begin,
+                &mut end,
+            );
+        }
+        begin..end
+    }
+
+    /// Finds suffixes matching at least `query[..min_len]`, extending the match
+    /// one base at a time until at most `max_hits` suffixes remain.
     pub fn extension_search(
         &self,
         text: &[u8],
         query: &[u8],
         min_len: usize,
         max_hits: usize,
-    ) -> Option<(Range<usize>, usize)> {
-        match self {
-            Self::FixedLengthBuckets(sa) => sa.extension_search(text, query, min_len, max_hits),
-            Self::VariableLengthBuckets(sa) => sa.extension_search(text, query, min_len, max_hits),
-            Self::Hashing(sa) => sa.extension_search(text, query, min_len, max_hits),
-            Self::Fringed(sa) => sa.extension_search(text, query, min_len, max_hits),
-            Self::SaHash(sa) => sa.extension_search(text, query, min_len, max_hits),
+    ) -> Extension {
+        let mut range = match self.prefix_search(text, query, min_len) {
+            Some(range) => range,
+            None => return Extension::NotFound,
+        };
+
+        let mut depth = min_len;
+        while depth < query.len() && range.len() > max_hits {
+            let narrowed = self.narrow(text, query, depth, range.clone());
+            if narrowed.is_empty() {
+                break;
+            }
+            range = narrowed;
+            depth += 1;
+        }
+
+        if range.len() > max_hits {
+            Extension::TooManyHits(range, depth)
+        } else {
+            Extension::Hits(range, depth)
         }
     }
 
+    /// Runs [`Self::extension_search`] for each of `queries`.
+    ///
+    /// The searches advance in turns of one binary search step, and each step
+    /// prefetches the suffix array entry or text base read by the next step of
+    /// the same search, so that its memory latency overlaps with the steps of
+    /// the other searches.
+    pub fn extension_search_batch(
+        &self,
+        text: &[u8],
+        queries: &[&[u8]],
+        min_len: usize,
+        max_hits: usize,
+    ) -> Vec<Extension> {
+        let array = self.array();
+
+        let mut searches: Vec<_> = queries
+            .iter()
+            .map(|query| BatchSearch::new(self.prefix_search(text, query, min_len), min_len))
+            .collect();
+        let mut active: Vec<_> = (0..queries.len())
+            .filter(|&i| searches[i].is_narrowing(queries[i], max_hits))
+            .collect();
+
+        while !active.is_empty() {
+            active.retain(|&i| searches[i].step(array, text, queries[i], max_hits));
+        }
+
+        searches
+            .into_iter()
+            .map(|search| search.into_extension(max_hits))
+            .collect()
+    }
+
+    /// Finds suffixes matching `query[..len]` with at most `max_mismatches` substitutions,
+    /// all of which occur after the exact core `query[..core_len]`.
+    ///
+    /// Returns one range per distinct matching substitution pattern.
+    pub fn mismatch_search(
+        &self,
+        text: &[u8],
+        query: &[u8],
+        len: usize,
+        core_len: usize,
+        max_mismatches: usize,
+    ) -> Vec<Range<usize>> {
+        assert!(core_len <= len && len <= query.len());
+
+        let mut ranges = Vec::new();
+        if let Some(range) = self.prefix_search(text, query, core_len) {
+            let mut pattern = query[..len].to_vec();
+            self.backtrack(
+                text,
+                &mut pattern,
+                core_len,
+                range,
+                max_mismatches,
+                &mut ranges,
+            );
+        }
+        ranges
+    }
+
+    fn backtrack(
+        &self,
+        text: &[u8],
+        pattern: &mut [u8],
+        depth: usize,
+        range: Range<usize>,
+        mismatches_left: usize,
+        ranges: &mut Vec<Range<usize>>,
+    ) {
+        if depth == pattern.len() {
+            ranges.push(range);
+            return;
+        }
+
+        let original = pattern[depth];
+        for code in 1..=4 {
+            let is_mismatch = code != original;
+            if is_mismatch && mismatches_left == 0 {
+                continue;
+            }
+            pattern[depth] = code;
+            let narrowed = self.narrow(text, pattern, depth, range.clone());
+            if !narrowed.is_empty() {
+                self.backtrack(
+                    text,
+                    pattern,
+                    depth + 1,
+                    narrowed,
+                    mismatches_left - is_mismatch as usize,
+                    ranges,
+                );
+            }
+        }
+        pattern[depth] = original;
+    }
+
     pub fn bucket_size_distribution(&self) -> BTreeMap<usize, usize> {
         match self {
             Self::FixedLengthBuckets(sa) => sa.bucket_size_distribution(),
@@ -131,6 +373,127 @@ impl SuffixArray {
     }
 }
 
+/// State of one search of [`SuffixArray::extension_search_batch`]
+struct BatchSearch {
+    /// Suffixes matching `query[..depth]`, or `None` if no suffix matches `query[..min_len]`
+    range: Option<Range<usize>>,
+    depth: usize,
+    /// Bounds of the binary search for the suffixes also matching `query[depth]`
+    lo: usize,
+    hi: usize,
+    /// Start of the narrowed range, once found
+    begin: Option<usize>,
+    probe: Probe,
+}
+
+/// Memory access pending in the current binary search step
+#[derive(Clone, Copy)]
+enum Probe {
+    Idle,
+    /// Suffix array index of the middle suffix
+    Index(usize),
+    /// Suffix array index of the middle suffix and the text position compared
+    Text(usize, usize),
+}
+
+impl BatchSearch {
+    fn new(range: Option<Range<usize>>, min_len: usize) -> Self {
+        let (lo, hi) = range
+            .as_ref()
+            .map_or((0, 0), |range| (range.start, range.end));
+        Self {
+            range,
+            depth: min_len,
+            lo,
+            hi,
+            begin: None,
+            probe: Probe::Idle,
+        }
+    }
+
+    fn is_narrowing(&self, query: &[u8], max_hits: usize) -> bool {
+        matches!(&self.range, Some(range) if self.depth < query.len() && range.len() > max_hits)
+    }
+
+    /// Advances the search by one memory access and returns whether it continues.
+    fn step(&mut self, array: &[u32], text: &[u8], query: &[u8], max_hits: usize) -> bool {
+        match self.probe {
+            Probe::Idle => {
+                let mid = self.lo + (self.hi - self.lo) / 2;
+                prefetch(array.as_ptr().wrapping_add(mid));
+                self.probe = Probe::Index(mid);
+            }
+            Probe::Index(mid) => {
+                let pos = array[mid] as usize + self.depth;
+                prefetch(text.as_ptr().wrapping_add(pos));
+                self.probe = Probe::Text(mid, pos);
+            }
+            Probe::Text(mid, pos) => {
+                self.probe = Probe::Idle;
+
+                // A suffix ending at the end of the text sorts before its extensions
+                let base = text.get(pos).copied();
+                let target = Some(query[self.depth]);
+                let is_before = match self.begin {
+                    None => base < target,
+                    Some(_) => base <= target,
+                };
+                if is_before {
+                    self.lo = mid + 1;
+                } else {
+                    self.hi = mid;
+                }
+
+                if self.lo == self.hi {
+                    return self.finish_bound(query, max_hits);
+                }
+            }
+        }
+        true
+    }
+
+    /// Records the bound just found and returns whether the search continues.
+    fn finish_bound(&mut self, query: &[u8], max_hits: usize) -> bool {
+        let begin = match self.begin {
+            Some(begin) => begin,
+            None => {
+                self.begin = Some(self.lo);
+                self.hi = self.range.as_ref().unwrap().end;
+                return self.lo < self.hi;
+            }
+        };
+
+        let narrowed = begin..self.lo;
+        if narrowed.is_empty() {
+            return false;
+        }
+        self.range = Some(narrowed.clone());
+        self.depth += 1;
+        self.begin = None;
+        self.lo = narrowed.start;
+        self.hi = narrowed.end;
+        self.is_narrowing(query, max_hits)
+    }
+
+    fn into_extension(self, max_hits: usize) -> Extension {
+        match self.range {
+            Some(range) if range.len() > max_hits => Extension::TooManyHits(range, self.depth),
+            Some(range) => Extension::Hits(range, self.depth),
+            None => Extension::NotFound,
+        }
+    }
+}
+
+#[inline]
+fn prefetch<T>(ptr: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::x86_64::_mm_prefetch(ptr as *const i8, std::arch::x86_64::_MM_HINT_T0);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = ptr;
+}
+
 unsafe fn equal_range(
     sa: &[u32],
     text_base: *const u8,
@@ -139,6 +502,11 @@ unsafe fn equal_range(
     begin: &mut usize,
     end: &mut usize,
 ) {
+    // Every suffix in the range matches an empty query
+    if query_begin == query_end {
+        return;
+    }
+
     let mut q_begin = query_begin;
     let mut q_end = q_begin;
     let mut t_begin = text_base;
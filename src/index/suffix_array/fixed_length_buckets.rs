@@ -1,12 +1,14 @@
-use crate::sequence;
+use crate::{index::buffer::Buffer, sequence};
+
+use super::sais;
 
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, ops::Range};
-use sufsort_rs::sufsort::SA;
 
 #[derive(Serialize, Deserialize)]
 pub struct FixedLengthBuckets {
-    array: Vec<u32>,
+    #[serde(skip)]
+    array: Buffer<u32>,
     offsets: Vec<u32>,
     bucket_width: usize,
 }
@@ -16,21 +18,14 @@ impl FixedLengthBuckets {
         assert!(text.len() <= u32::MAX as usize + 1);
         assert!(bucket_width * 2 < std::mem::size_of::<usize>() * 8);
 
-        let sa = SA::<i32>::new(text);
-        let array: Vec<_> = sa.sarray.into_iter().map(|x| x as u32).collect();
+        let array = sais::suffix_array(text);
 
         let buckets_len = 1 << (2 * bucket_width);
         let mut counts = vec![0u32; buckets_len];
         for i in 0..=(text.len() - bucket_width) {
-            let seq = &text[i..i + bucket_width];
-            if seq.iter().any(|x| *x == 0 || *x == sequence::DUMMY_CODE) {
-                continue;
-            }
-            let mut idx = 0;
-            for (j, x) in seq.iter().enumerate() {
-                idx |= (sequence::code_to_two_bit(*x) as usize) << (2 * j);
+            if let Some(idx) = bucket_index(&text[i..i + bucket_width]) {
+                counts[idx] += 1;
             }
-            counts[idx] += 1;
         }
 
         let mut cum_sum = 0;
@@ -49,26 +44,125 @@ impl FixedLengthBuckets {
             if *s as usize + bucket_width > text.len() {
                 continue;
             }
-            let seq = &text[*s as usize..][..bucket_width];
-            if seq.iter().any(|x| *x == 0 || *x == sequence::DUMMY_CODE) {
-                continue;
-            }
-
-            let mut idx = 0;
-            for (j, x) in seq.iter().enumerate() {
-                idx |= (sequence::code_to_two_bit(*x) as usize) << (2 * j);
-            }
+            let idx = match bucket_index(&text[*s as usize..][..bucket_width]) {
+                Some(idx) => idx,
+                None => continue,
+            };
             let p = pos[idx] as usize;
             ssa[p] = *s;
             pos[idx] += 1;
         }
 
         Self {
-            array: ssa,
+            array: ssa.into(),
             offsets,
             bucket_width,
         }
     }
+
+    /// Inserts the suffixes starting at `start` or later, where `text[..start]` is
+    /// the text the array was built from, by merging them into their buckets.
+    ///
+    /// Suffixes are only ordered up to their first delimiter, which queries never
+    /// match, so the suffixes of the old text keep their order.
+    pub fn append(&mut self, text: &[u8], start: usize) {
+        assert!(text.len() <= u32::MAX as usize + 1);
+
+        let last = (text.len() + 1).saturating_sub(self.bucket_width);
+        let mut suffixes: Vec<_> = (start..last)
+            .filter_map(|s| {
+                bucket_index(&text[s..s + self.bucket_width]).map(|idx| (idx, s as u32))
+            })
+            .collect();
+        suffixes.sort_unstable_by(|(idx1, s1), (idx2, s2)| {
+            idx1.cmp(idx2)
+                .then_with(|| text[*s1 as usize..].cmp(&text[*s2 as usize..]))
+        });
+
+        self.merge_suffixes(text, suffixes.into_iter());
+    }
+
+    /// Merges the suffix array `other`, whose positions are shifted by `offset` in `text`.
+    pub fn merge(&mut self, other: &Self, text: &[u8], offset: usize) {
+        assert_eq!(self.bucket_width, other.bucket_width);
+        assert!(text.len() <= u32::MAX as usize + 1);
+
+        let suffixes = other
+            .offsets
+            .windows(2)
+            .enumerate()
+            .flat_map(|(idx, bucket)| {
+                other.array[bucket[0] as usize..bucket[1] as usize]
+                    .iter()
+                    .map(move |&s| (idx, s + offset as u32))
+            });
+        self.merge_suffixes(text, suffixes);
+    }
+
+    /// Returns the array of the suffixes kept by `f`, which maps their positions to
+    /// those of suffixes starting with the same bases, up to a delimiter, in a new text.
+    pub fn filter_map<F: FnMut(u32) -> Option<u32>>(&self, mut f: F) -> Self {
+        let mut array = Vec::new();
+        let mut offsets = Vec::with_capacity(self.offsets.len());
+        for bucket in self.offsets.windows(2) {
+            offsets.push(array.len() as u32);
+            array.extend(
+                self.array[bucket[0] as usize..bucket[1] as usize]
+                    .iter()
+                    .filter_map(|&s| f(s)),
+            );
+        }
+        offsets.push(array.len() as u32);
+
+        Self {
+            array: array.into(),
+            offsets,
+            bucket_width: self.bucket_width,
+        }
+    }
+
+    pub fn bucket_width(&self) -> usize {
+        self.bucket_width
+    }
+
+    /// Merges `suffixes`, given with their buckets and sorted, into the buckets.
+    fn merge_suffixes<I: Iterator<Item = (usize, u32)>>(&mut self, text: &[u8], suffixes: I) {
+        let mut array = Vec::with_capacity(self.array.len() + suffixes.size_hint().0);
+        let mut offsets = Vec::with_capacity(self.offsets.len());
+        let mut suffixes = suffixes.peekable();
+        for idx in 0..self.offsets.len() - 1 {
+            offsets.push(array.len() as u32);
+            let bucket = &self.array[self.offsets[idx] as usize..self.offsets[idx + 1] as usize];
+            for &old in bucket {
+                while let Some((_, s)) = suffixes
+                    .next_if(|&(i, s)| i == idx && text[s as usize..] < text[old as usize..])
+                {
+                    array.push(s);
+                }
+                array.push(old);
+            }
+            while let Some((_, s)) = suffixes.next_if(|&(i, _)| i == idx) {
+                array.push(s);
+            }
+        }
+        offsets.push(array.len() as u32);
+
+        self.array = array.into();
+        self.offsets = offsets;
+    }
+}
+
+/// Returns the bucket of suffixes starting with `seq`, or `None` if it contains
+/// a delimiter or an ambiguous base.
+fn bucket_index(seq: &[u8]) -> Option<usize> {
+    let mut idx = 0;
+    for (j, x) in seq.iter().enumerate() {
+        if *x == 0 || *x == sequence::DUMMY_CODE {
+            return None;
+        }
+        idx |= (sequence::code_to_two_bit(*x) as usize) << (2 * j);
+    }
+    Some(idx)
 }
 
 impl super::SuffixArrayVariant for FixedLengthBuckets {
@@ -76,13 +170,15 @@ impl super::SuffixArrayVariant for FixedLengthBuckets {
         self.array[i] as usize
     }
 
-    fn extension_search(
-        &self,
-        text: &[u8],
-        query: &[u8],
-        min_len: usize,
-        max_hits: usize,
-    ) -> Option<(Range<usize>, usize)> {
+    fn array(&self) -> &[u32] {
+        &self.array
+    }
+
+    fn array_mut(&mut self) -> &mut Buffer<u32> {
+        &mut self.array
+    }
+
+    fn prefix_search(&self, text: &[u8], query: &[u8], min_len: usize) -> Option<Range<usize>> {
         debug_assert!(self.bucket_width <= min_len && min_len <= query.len());
 
         let mut idx = 0;
@@ -110,31 +206,11 @@ impl super::SuffixArrayVariant for FixedLengthBuckets {
             return None;
         }
 
-        let mut depth = min_len;
-        let query_len = query.len();
-
-        while depth < query_len && end - begin > max_hits {
-            unsafe {
-                super::equal_range(
-                    &self.array,
-                    text.as_ptr().add(depth),
-                    query.as_ptr().add(depth),
-                    query.as_ptr().add(depth + 1),
-                    &mut begin,
-                    &mut end,
-                );
-            }
-            if begin == end {
-                return None;
-            }
-            depth += 1;
-        }
+        Some(begin..end)
+    }
 
-        if depth == query_len && end - begin > max_hits {
-            None
-        } else {
-            Some((begin..end, depth))
-        }
+    fn min_prefix_len(&self) -> usize {
+        self.bucket_width
     }
 
     fn bucket_size_distribution(&self) -> BTreeMap<usize, usize> {
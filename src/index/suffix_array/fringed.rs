@@ -1,12 +1,14 @@
-use crate::sequence;
+use crate::{index::buffer::Buffer, sequence};
+
+use super::sais;
 
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, ops::Range};
-use sufsort_rs::sufsort::SA;
 
 #[derive(Serialize, Deserialize)]
 pub struct Fringed {
-    array: Vec<u32>,
+    #[serde(skip)]
+    array: Buffer<u32>,
     offsets: Vec<u32>,
     k: usize,
     l: usize,
@@ -18,8 +20,7 @@ impl Fringed {
 
         let k = l + 16;
 
-        let sa = SA::<i32>::new(text);
-        let array: Vec<_> = sa.sarray.into_iter().map(|x| x as u32).collect();
+        let array = sais::suffix_array(text);
 
         let offsets_len = 1 << (2 * l);
         let mut left_to_indices = vec![sorted_list::SortedList::new(); offsets_len];
@@ -92,7 +93,7 @@ impl Fringed {
         }
 
         Self {
-            array: ssa,
+            array: ssa.into(),
             offsets,
             k,
             l,
@@ -105,13 +106,15 @@ impl super::SuffixArrayVariant for Fringed {
         self.array[index] as usize
     }
 
-    fn extension_search(
-        &self,
-        text: &[u8],
-        query: &[u8],
-        min_len: usize,
-        max_hits: usize,
-    ) -> Option<(Range<usize>, usize)> {
+    fn array(&self) -> &[u32] {
+        &self.array
+    }
+
+    fn array_mut(&mut self) -> &mut Buffer<u32> {
+        &mut self.array
+    }
+
+    fn prefix_search(&self, text: &[u8], query: &[u8], min_len: usize) -> Option<Range<usize>> {
         let mut left = 0;
         for (j, x) in query[..self.l].iter().enumerate() {
             left |= (sequence::code_to_two_bit(*x) as u32) << (2 * j);
@@ -159,31 +162,11 @@ impl super::SuffixArrayVariant for Fringed {
             return None;
         }
 
-        let mut depth = min_len;
-        let query_len = query.len();
-        while depth < query_len && end - begin > max_hits {
-            unsafe {
-                super::equal_range(
-                    &self.array,
-                    text.as_ptr().add(depth),
-                    query.as_ptr().add(depth),
-                    query.as_ptr().add(depth + 1),
-                    &mut begin,
-                    &mut end,
-                );
-            }
-
-            if begin == end {
-                return None;
-            }
-            depth += 1;
-        }
+        Some(begin..end)
+    }
 
-        if depth == query_len && end - begin > max_hits {
-            None
-        } else {
-            Some((begin..end, depth))
-        }
+    fn min_prefix_len(&self) -> usize {
+        self.k
     }
 
     fn bucket_size_distribution(&self) -> BTreeMap<usize, usize> {
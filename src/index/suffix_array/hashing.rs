@@ -1,12 +1,14 @@
-use crate::{hash::HashFunc, sequence};
+use crate::{hash::HashFunc, index::buffer::Buffer, sequence};
+
+use super::sais;
 
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, ops::Range};
-use sufsort_rs::sufsort::SA;
 
 #[derive(Serialize, Deserialize)]
 pub struct Hashing {
-    array: Vec<u32>,
+    #[serde(skip)]
+    array: Buffer<u32>,
     offsets: Vec<u32>,
     k: usize,
     hash_func: HashFunc,
@@ -17,8 +19,7 @@ impl Hashing {
     pub fn new(text: &[u8], k: usize, bits: usize, hash_func: HashFunc) -> Self {
         assert!(text.len() <= u32::MAX as usize + 1);
 
-        let sa = SA::<i32>::new(text);
-        let sa = sa.sarray.into_iter().map(|x| x as u32);
+        let sa = sais::suffix_array(text);
 
         let hashtable_len = 1 << bits;
         let mask = (hashtable_len - 1) as u32;
@@ -58,7 +59,7 @@ impl Hashing {
         }
 
         Self {
-            array,
+            array: array.into(),
             offsets,
             k,
             hash_func,
@@ -72,13 +73,15 @@ impl super::SuffixArrayVariant for Hashing {
         self.array[index] as usize
     }
 
-    fn extension_search(
-        &self,
-        text: &[u8],
-        query: &[u8],
-        min_len: usize,
-        max_hits: usize,
-    ) -> Option<(Range<usize>, usize)> {
+    fn array(&self) -> &[u32] {
+        &self.array
+    }
+
+    fn array_mut(&mut self) -> &mut Buffer<u32> {
+        &mut self.array
+    }
+
+    fn prefix_search(&self, text: &[u8], query: &[u8], min_len: usize) -> Option<Range<usize>> {
         let idx = (self.hash_func.hash(&query[..self.k]) & self.mask) as usize;
         let mut begin = self.offsets[idx] as usize;
         let mut end = self.offsets[idx + 1] as usize;
@@ -100,31 +103,11 @@ impl super::SuffixArrayVariant for Hashing {
             return None;
         }
 
-        let mut depth = min_len;
-        let query_len = query.len();
-        while depth < query_len && end - begin > max_hits {
-            unsafe {
-                super::equal_range(
-                    &self.array,
-                    text.as_ptr().add(depth),
-                    query.as_ptr().add(depth),
-                    query.as_ptr().add(depth + 1),
-                    &mut begin,
-                    &mut end,
-                );
-            }
-
-            if begin == end {
-                return None;
-            }
-            depth += 1;
-        }
+        Some(begin..end)
+    }
 
-        if depth == query_len && end - begin > max_hits {
-            None
-        } else {
-            Some((begin..end, depth))
-        }
+    fn min_prefix_len(&self) -> usize {
+        self.k
     }
 
     fn bucket_size_distribution(&self) -> BTreeMap<usize, usize> {
@@ -1,8 +1,9 @@
-use crate::{hash::HashFunc, sequence};
+use crate::{hash::HashFunc, index::buffer::Buffer, sequence};
+
+use super::sais;
 
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, ops::Range};
-use sufsort_rs::sufsort::SA;
 
 // Grabowski, S., and M. Raniszewski. "Compact and Hash Based Variants of the Suffix Array."
 // Bulletin of the Polish Academy of Sciences: Technical Sciences 65, no. No 4 (2017): 407–18.
@@ -11,7 +12,8 @@ const LUT_WIDTH: usize = 2;
 
 #[derive(Serialize, Deserialize)]
 pub struct SaHash {
-    array: Vec<u32>,
+    #[serde(skip)]
+    array: Buffer<u32>,
     lut: Vec<(u32, u32)>,
     hashtable: Vec<(u32, u32)>,
     k: usize,
@@ -23,8 +25,7 @@ impl SaHash {
     pub fn new(text: &[u8], k: usize, bits: usize, hash_func: HashFunc) -> Self {
         assert!(text.len() <= u32::MAX as usize + 1);
 
-        let sa = SA::<i32>::new(text);
-        let sa = sa.sarray.into_iter().map(|x| x as u32);
+        let sa = sais::suffix_array(text);
 
         let mut array = Vec::with_capacity(sa.len());
 
@@ -87,7 +88,7 @@ impl SaHash {
         assert_eq!(lut_cum_sum as usize, array.len());
 
         Self {
-            array,
+            array: array.into(),
             lut,
             hashtable,
             k,
@@ -102,13 +103,15 @@ impl super::SuffixArrayVariant for SaHash {
         self.array[index] as usize
     }
 
-    fn extension_search(
-        &self,
-        text: &[u8],
-        query: &[u8],
-        min_len: usize,
-        max_hits: usize,
-    ) -> Option<(Range<usize>, usize)> {
+    fn array(&self) -> &[u32] {
+        &self.array
+    }
+
+    fn array_mut(&mut self) -> &mut Buffer<u32> {
+        &mut self.array
+    }
+
+    fn prefix_search(&self, text: &[u8], query: &[u8], min_len: usize) -> Option<Range<usize>> {
         let mut idx = 0;
         for (j, x) in query[..LUT_WIDTH].iter().enumerate() {
             idx |= (sequence::code_to_two_bit(*x) as usize) << (2 * (LUT_WIDTH - j - 1));
@@ -148,31 +151,11 @@ impl super::SuffixArrayVariant for SaHash {
             return None;
         }
 
-        let mut depth = min_len;
-        let query_len = query.len();
-        while depth < query_len && end - begin > max_hits {
-            unsafe {
-                super::equal_range(
-                    &self.array,
-                    text.as_ptr().add(depth),
-                    query.as_ptr().add(depth),
-                    query.as_ptr().add(depth + 1),
-                    &mut begin,
-                    &mut end,
-                );
-            }
-
-            if begin == end {
-                return None;
-            }
-            depth += 1;
-        }
+        Some(begin..end)
+    }
 
-        if depth == query_len && end - begin > max_hits {
-            None
-        } else {
-            Some((begin..end, depth))
-        }
+    fn min_prefix_len(&self) -> usize {
+        self.k
     }
 
     fn bucket_size_distribution(&self) -> BTreeMap<usize, usize> {
@@ -0,0 +1,179 @@
+// Suffix array construction by induced sorting (SA-IS), after Nong, Zhang and Chan,
+// "Two Efficient Algorithms for Linear Time Suffix Array Construction" (2011)
+
+const EMPTY: u32 = u32::MAX;
+
+// Texts shorter than this are sorted by comparing suffixes
+const NAIVE_MAX_LEN: usize = 10;
+
+trait Symbol: Copy + Ord {
+    fn rank(self) -> usize;
+}
+
+impl Symbol for u8 {
+    fn rank(self) -> usize {
+        self as usize
+    }
+}
+
+impl Symbol for u32 {
+    fn rank(self) -> usize {
+        self as usize
+    }
+}
+
+/// Returns the starting positions of the suffixes of `text` in lexicographic order.
+pub fn suffix_array(text: &[u8]) -> Vec<u32> {
+    assert!(text.len() < EMPTY as usize);
+    sais(text, u8::MAX as usize)
+}
+
+// Sorts the suffixes of `s`, whose symbols rank at most `upper`
+fn sais<T: Symbol>(s: &[T], upper: usize) -> Vec<u32> {
+    let n = s.len();
+    if n < NAIVE_MAX_LEN {
+        let mut sa: Vec<u32> = (0..n as u32).collect();
+        sa.sort_unstable_by(|&a, &b| s[a as usize..].cmp(&s[b as usize..]));
+        return sa;
+    }
+
+    // Whether each suffix is smaller than the next one (S-type) or larger (L-type)
+    let mut ls = vec![false; n];
+    for i in (0..n - 1).rev() {
+        ls[i] = if s[i] == s[i + 1] {
+            ls[i + 1]
+        } else {
+            s[i] < s[i + 1]
+        };
+    }
+
+    // Starts of the L-type and the S-type suffixes of each symbol's bucket
+    let mut sum_l = vec![0; upper + 1];
+    let mut sum_s = vec![0; upper + 1];
+    for i in 0..n {
+        if ls[i] {
+            sum_l[s[i].rank() + 1] += 1;
+        } else {
+            sum_s[s[i].rank()] += 1;
+        }
+    }
+    for c in 0..=upper {
+        sum_s[c] += sum_l[c];
+        if c < upper {
+            sum_l[c + 1] += sum_s[c];
+        }
+    }
+
+    let mut sa = vec![EMPTY; n];
+    // Sorts all suffixes from the leftmost S-type ones (LMS) in `lms` order
+    let induce = |sa: &mut [u32], lms: &[u32]| {
+        sa.iter_mut().for_each(|x| *x = EMPTY);
+        let mut buf = sum_s.clone();
+        for &d in lms {
+            let c = s[d as usize].rank();
+            sa[buf[c]] = d;
+            buf[c] += 1;
+        }
+        buf.copy_from_slice(&sum_l);
+        let c = s[n - 1].rank();
+        sa[buf[c]] = n as u32 - 1;
+        buf[c] += 1;
+        for i in 0..n {
+            let v = sa[i];
+            if v != EMPTY && v >= 1 && !ls[v as usize - 1] {
+                let c = s[v as usize - 1].rank();
+                sa[buf[c]] = v - 1;
+                buf[c] += 1;
+            }
+        }
+        buf.copy_from_slice(&sum_l);
+        for i in (0..n).rev() {
+            let v = sa[i];
+            if v != EMPTY && v >= 1 && ls[v as usize - 1] {
+                let c = s[v as usize - 1].rank() + 1;
+                buf[c] -= 1;
+                sa[buf[c]] = v - 1;
+            }
+        }
+    };
+
+    let mut lms_map = vec![EMPTY; n + 1];
+    let mut lms = Vec::new();
+    for i in 1..n {
+        if !ls[i - 1] && ls[i] {
+            lms_map[i] = lms.len() as u32;
+            lms.push(i as u32);
+        }
+    }
+    induce(&mut sa, &lms);
+    if lms.is_empty() {
+        return sa;
+    }
+
+    // Names the LMS substrings by their order, and sorts the LMS suffixes by
+    // sorting the suffixes of the string of their names
+    let m = lms.len();
+    let mut sorted_lms: Vec<u32> = sa
+        .iter()
+        .copied()
+        .filter(|&v| lms_map[v as usize] != EMPTY)
+        .collect();
+    let mut names = vec![0u32; m];
+    let mut upper_name = 0;
+    for i in 1..m {
+        let (mut l, mut r) = (sorted_lms[i - 1] as usize, sorted_lms[i] as usize);
+        let end = |p: usize| {
+            let next = lms_map[p] as usize + 1;
+            if next < m {
+                lms[next] as usize
+            } else {
+                n
+            }
+        };
+        let (end_l, end_r) = (end(l), end(r));
+        let mut same = end_l - l == end_r - r;
+        if same {
+            while l < end_l && s[l] == s[r] {
+                l += 1;
+                r += 1;
+            }
+            if l == n || r == n || s[l] != s[r] {
+                same = false;
+            }
+        }
+        if !same {
+            upper_name += 1;
+        }
+        names[lms_map[sorted_lms[i] as usize] as usize] = upper_name;
+    }
+
+    let names_sa = sais(&names, upper_name as usize);
+    for (sorted, &i) in sorted_lms.iter_mut().zip(&names_sa) {
+        *sorted = lms[i as usize];
+    }
+    induce(&mut sa, &sorted_lms);
+    sa
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_sorted_suffixes() {
+        let mut state = 1u32;
+        for len in (0..200).chain([1000, 5000].iter().copied()) {
+            for &alphabet in &[1, 2, 4, 256] {
+                let text: Vec<u8> = (0..len)
+                    .map(|_| {
+                        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                        ((state >> 16) % alphabet) as u8
+                    })
+                    .collect();
+                let mut expected: Vec<u32> = (0..len as u32).collect();
+                expected.sort_by(|&a, &b| text[a as usize..].cmp(&text[b as usize..]));
+                assert_eq!(suffix_array(&text), expected, "{:?}", text);
+            }
+        }
+    }
+}
@@ -1,12 +1,14 @@
-use crate::sequence;
+use crate::{index::buffer::Buffer, sequence};
+
+use super::sais;
 
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, ops::Range};
-use sufsort_rs::sufsort::SA;
 
 #[derive(Serialize, Deserialize)]
 pub struct VariableLengthBuckets {
-    array: Vec<u32>,
+    #[serde(skip)]
+    array: Buffer<u32>,
     offsets: Vec<u32>,
     buckets: Vec<u32>,
     k: usize,
@@ -17,7 +19,7 @@ impl VariableLengthBuckets {
     pub fn new(text: &[u8], k: usize, f: f64) -> Self {
         assert!(text.len() <= u32::MAX as usize + 1);
 
-        let sa = SA::<i32>::new(text);
+        let sa = sais::suffix_array(text);
 
         let offsets_len = 1 << (2 * k);
         let mut counts = vec![0u32; offsets_len];
@@ -45,7 +47,7 @@ impl VariableLengthBuckets {
         let mut ssa = Vec::new();
         let mut buckets = vec![u32::MAX; buckets_len];
         let mut prev_bucket = 0;
-        for s in sa.sarray.into_iter().map(|x| x as u32) {
+        for s in sa {
             if s as usize + k > text.len() {
                 continue;
             }
@@ -94,7 +96,7 @@ impl VariableLengthBuckets {
         }
 
         Self {
-            array: ssa,
+            array: ssa.into(),
             offsets,
             buckets,
             k,
@@ -108,13 +110,15 @@ impl super::SuffixArrayVariant for VariableLengthBuckets {
         self.array[index] as usize
     }
 
-    fn extension_search(
-        &self,
-        text: &[u8],
-        query: &[u8],
-        min_len: usize,
-        max_hits: usize,
-    ) -> Option<(Range<usize>, usize)> {
+    fn array(&self) -> &[u32] {
+        &self.array
+    }
+
+    fn array_mut(&mut self) -> &mut Buffer<u32> {
+        &mut self.array
+    }
+
+    fn prefix_search(&self, text: &[u8], query: &[u8], min_len: usize) -> Option<Range<usize>> {
         debug_assert!(self.k <= min_len && min_len <= query.len());
 
         let mut idx = 0;
@@ -154,31 +158,18 @@ impl super::SuffixArrayVariant for VariableLengthBuckets {
             return None;
         }
 
-        let mut depth = min_len;
-        let query_len = query.len();
-
-        while depth < query_len && end - begin > max_hits {
-            unsafe {
-                super::equal_range(
-                    &self.array,
-                    text.as_ptr().add(depth),
-                    query.as_ptr().add(depth),
-                    query.as_ptr().add(depth + 1),
-                    &mut begin,
-                    &mut end,
-                );
-            }
-            if begin == end {
-                return None;
-            }
-            depth += 1;
-        }
+        Some(begin..end)
+    }
 
-        if depth == query_len && end - begin > max_hits {
-            None
-        } else {
-            Some((begin..end, depth))
-        }
+    fn min_prefix_len(&self) -> usize {
+        // Buckets of 4^w entries subdivide their suffixes by w more bases
+        let max_width = self
+            .offsets
+            .windows(2)
+            .map(|w| ((w[1] - w[0]).trailing_zeros() / 2) as usize)
+            .max()
+            .unwrap_or(0);
+        self.k + max_width
     }
 
     fn bucket_size_distribution(&self) -> BTreeMap<usize, usize> {
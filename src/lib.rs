@@ -1,5 +1,8 @@
+pub mod barcode;
 pub mod hash;
 pub mod index;
 pub mod mapper;
 pub mod sequence;
+pub mod trim;
 pub mod utils;
+pub mod vcf;
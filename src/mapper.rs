@@ -1,27 +1,201 @@
+pub mod align;
+mod chain;
+
 use crate::{
-    index::{Index, SequenceId},
+    hash::HashFunc,
+    index::{self, suffix_array::Extension, Index, SequenceId, ShardedIndex},
     sequence,
 };
-use rustc_hash::FxHashMap;
+use align::{AlignParams, Alignment, AlignmentMode, Cigar, CigarOp, ScoringScheme};
+use chain::Chain;
+use itertools::Either;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{cmp::Reverse, ops::Range};
 
-#[derive(Debug, Clone, Copy)]
-pub enum LibraryType {
+/// Relative orientation of the mates of a pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MateOrientation {
+    /// Mates face each other (`fr`)
+    Inward,
+    /// Mates face away from each other (`rf`)
+    Outward,
+    /// Mates have the same direction (`ff`)
+    Matching,
+}
+
+/// Strand of the sequenced fragment from which read 1 comes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strandedness {
     Unstranded,
-    FirstStrand,
-    SecondStrand,
+    /// Read 1 comes from the forward strand
+    Forward,
+    /// Read 1 comes from the reverse strand
+    Reverse,
+    /// Inferred from a sample of reads with `StrandEvidence`.
+    /// Reads are mapped as unstranded until it is resolved.
+    Auto,
+}
+
+/// Library type as written in salmon's library format codes, e.g. `IU`, `ISR`, `SF` or `A`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LibraryType {
+    /// `None` for single-end libraries and for paired-end libraries whose mates
+    /// may have any orientation
+    pub orientation: Option<MateOrientation>,
+    pub strandedness: Strandedness,
+}
+
+impl LibraryType {
+    pub const UNSTRANDED: Self = Self {
+        orientation: None,
+        strandedness: Strandedness::Unstranded,
+    };
+
+    /// Strand that a read must map to, or `None` if it may map to both strands
+    pub fn read_strand(&self, is_read1: bool) -> Option<Strand> {
+        let read1_strand = match self.strandedness {
+            Strandedness::Unstranded | Strandedness::Auto => return None,
+            Strandedness::Forward => Strand::Forward,
+            Strandedness::Reverse => Strand::Reverse,
+        };
+        if is_read1 || self.orientation == Some(MateOrientation::Matching) {
+            Some(read1_strand)
+        } else {
+            Some(read1_strand.opposite())
+        }
+    }
+}
+
+impl std::fmt::Display for LibraryType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.orientation {
+            Some(MateOrientation::Inward) => write!(f, "I")?,
+            Some(MateOrientation::Outward) => write!(f, "O")?,
+            Some(MateOrientation::Matching) => write!(f, "M")?,
+            None => {}
+        }
+        match self.strandedness {
+            Strandedness::Unstranded => write!(f, "U"),
+            Strandedness::Forward => write!(f, "SF"),
+            Strandedness::Reverse => write!(f, "SR"),
+            Strandedness::Auto => write!(f, "A"),
+        }
+    }
 }
 
 impl std::str::FromStr for LibraryType {
     type Err = String;
 
+    fn from_str(s: &str) -> Result<Self, String> {
+        let error = || {
+            format!(
+                "Unknown library type {}. Valid values are salmon's library format codes \
+            (U, SF, SR, A, IU, ISF, ISR, IA, OU, OSF, OSR, OA, MU, MSF, MSR, MA) \
+            and fr-unstranded, fr-firststrand, fr-secondstrand, auto",
+                s
+            )
+        };
+
+        let code = match &s.to_lowercase()[..] {
+            "fr-unstranded" => "IU".to_owned(),
+            "fr-firststrand" => "ISR".to_owned(),
+            "fr-secondstrand" => "ISF".to_owned(),
+            "auto" => "A".to_owned(),
+            _ => s.to_uppercase(),
+        };
+
+        let orientation = match code.chars().next() {
+            Some('I') => Some(MateOrientation::Inward),
+            Some('O') => Some(MateOrientation::Outward),
+            Some('M') => Some(MateOrientation::Matching),
+            _ => None,
+        };
+        let strandedness = match &code[orientation.map_or(0, |_| 1)..] {
+            "U" => Strandedness::Unstranded,
+            "SF" => Strandedness::Forward,
+            "SR" => Strandedness::Reverse,
+            "A" => Strandedness::Auto,
+            _ => return Err(error()),
+        };
+
+        Ok(Self {
+            orientation,
+            strandedness,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Seeding {
+    /// Seeds start every `sparsity` bases and are extended rightward
+    /// until they have at most `seed_max_hits` hits.
+    Sparse,
+    /// Super-maximal exact matches, i.e. exact matches of at least
+    /// `seed_min_len` bases that are not contained in any other match.
+    Smem,
+    /// Seeds start at `(w, k)` minimizers of the query, where `k` is `seed_min_len`,
+    /// and are extended like sparse seeds.
+    Minimizer,
+}
+
+impl std::str::FromStr for Seeding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match &s.to_lowercase()[..] {
+            "sparse" => Ok(Self::Sparse),
+            "smem" => Ok(Self::Smem),
+            "minimizer" => Ok(Self::Minimizer),
+            _ => Err(format!(
+                "Unknown seeding strategy {}. Valid values are: sparse, smem, minimizer",
+                s
+            )),
+        }
+    }
+}
+
+/// Combinations of mapping options suited to common kinds of reads and references
+#[derive(Debug, Clone, Copy)]
+pub enum Preset {
+    /// Short reads against a transcriptome
+    SrTranscriptome,
+    /// Short reads against a genome, allowing introns
+    SrGenomeSpliced,
+    /// Noisy long reads such as Nanopore and PacBio cDNA reads
+    LongRead,
+    /// Short reads of small RNAs such as miRNAs
+    SmallRna,
+}
+
+impl Preset {
+    pub fn scoring(self) -> ScoringScheme {
+        match self {
+            Self::SrGenomeSpliced => ScoringScheme {
+                splice_penalty: 8,
+                ..ScoringScheme::default()
+            },
+            Self::SmallRna => ScoringScheme {
+                mismatch_penalty: 6,
+                gap_open: 8,
+                ..ScoringScheme::default()
+            },
+            Self::SrTranscriptome | Self::LongRead => ScoringScheme::default(),
+        }
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = String;
+
     fn from_str(s: &str) -> Result<Self, String> {
         match &s.to_lowercase()[..] {
-            "fr-unstranded" => Ok(Self::Unstranded),
-            "fr-firststrand" => Ok(Self::FirstStrand),
-            "fr-secondstrand" => Ok(Self::SecondStrand),
+            "sr-transcriptome" => Ok(Self::SrTranscriptome),
+            "sr-genome-spliced" => Ok(Self::SrGenomeSpliced),
+            "long-read" => Ok(Self::LongRead),
+            "small-rna" => Ok(Self::SmallRna),
             _ => Err(format!(
-                "Unknown library type {}. \
-            Valid values are: fr-unstranded, fr-firststrand, fr-secondstrand",
+                "Unknown preset {}. \
+            Valid values are: sr-transcriptome, sr-genome-spliced, long-read, small-rna",
                 s
             )),
         }
@@ -59,153 +233,2183 @@ pub struct Anchor {
     len: usize,
 }
 
+struct Seed {
+    /// Shard of the index whose suffix array `range` is in
+    shard: usize,
+    query_pos: usize,
+    range: Range<usize>,
+    len: usize,
+    /// Suffix array indices of a random sample of the hits in `range`,
+    /// which are used instead of all of them
+    sample: Option<Vec<usize>>,
+}
+
+impl Seed {
+    /// Returns the suffix array indices of the hits used.
+    fn hits(&self) -> impl Iterator<Item = usize> + '_ {
+        match &self.sample {
+            Some(sample) => Either::Left(sample.iter().copied()),
+            None => Either::Right(self.range.clone()),
+        }
+    }
+}
+
+/// Outcome of searching a query in every shard as in one index made of all of them,
+/// with the suffix array ranges of the shards whose suffixes match its first bases
+enum Matches {
+    /// Ranges of at most the maximum number of hits in total, matching the given
+    /// number of bases
+    Hits(Vec<(usize, Range<usize>)>, usize),
+    /// Ranges of the longest match found, which still has more than the maximum
+    /// number of hits in total
+    TooManyHits(Vec<(usize, Range<usize>)>, usize),
+    NotFound,
+}
+
 pub struct Mapping {
     pub seq_id: SequenceId,
     pub pos: usize,
     pub strand: Strand,
     pub score: i32,
+    pub cigar: Cigar,
+    pub edit_distance: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Outcome {
+    Mapped,
+    /// The read is shorter than `seed_min_len`
+    TooShort,
+    /// No seed position could be chosen, e.g. all k-mers contain ambiguous bases
+    NoSeeds,
+    /// No seed had any hit
+    NoHits,
+    /// Every seed with hits had more than `seed_max_hits` hits
+    Repetitive,
+    /// A decoy sequence matched the read better than any other sequence
+    Decoy,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Mapped => "mapped",
+            Self::TooShort => "too_short",
+            Self::NoSeeds => "no_seeds",
+            Self::NoHits => "no_hits",
+            Self::Repetitive => "repetitive",
+            Self::Decoy => "decoy",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SeedStats {
+    pub num_tried: usize,
+    pub num_with_hits: usize,
+    pub num_over_cap: usize,
+}
+
+pub struct MapResult {
+    pub mappings: Vec<Mapping>,
+    /// Alignments of the other parts of a chimeric read, complementing `mappings[0]`
+    pub supplementary: Vec<Mapping>,
+    pub outcome: Outcome,
+    pub seed_stats: SeedStats,
+    /// Number of query bases covered by the anchors of the best chain
+    pub best_coverage: usize,
+}
+
+pub struct PairMapResult {
+    pub read1: MapResult,
+    pub read2: MapResult,
+    /// Whether the first mappings of both reads form a concordant pair
+    pub is_concordant: bool,
+}
+
+impl MapResult {
+    fn unmapped(outcome: Outcome, seed_stats: SeedStats) -> Self {
+        Self {
+            mappings: Vec::new(),
+            supplementary: Vec::new(),
+            outcome,
+            seed_stats,
+            best_coverage: 0,
+        }
+    }
+}
+
+// Fraction of read 1 mappings on one strand above which a library is stranded
+const STRANDED_FRACTION: f64 = 0.8;
+
+/// Strands of the best mappings of reads mapped as unstranded, relative to read 1
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StrandEvidence {
+    pub forward: usize,
+    pub reverse: usize,
+    /// Reads whose best mappings are on both strands
+    pub ambiguous: usize,
+}
+
+impl StrandEvidence {
+    pub fn add(&mut self, result: &MapResult, is_read1: bool) {
+        let best = match result.mappings.first() {
+            Some(mapping) => mapping,
+            None => return,
+        };
+        let is_ambiguous = result
+            .mappings
+            .iter()
+            .take_while(|mapping| mapping.score == best.score)
+            .any(|mapping| mapping.strand != best.strand);
+        if is_ambiguous {
+            self.ambiguous += 1;
+        } else if best.strand.is_forward() == is_read1 {
+            self.forward += 1;
+        } else {
+            self.reverse += 1;
+        }
+    }
+
+    pub fn infer(&self) -> Strandedness {
+        let num_stranded = self.forward + self.reverse;
+        if num_stranded == 0 {
+            return Strandedness::Unstranded;
+        }
+        if self.forward as f64 >= num_stranded as f64 * STRANDED_FRACTION {
+            Strandedness::Forward
+        } else if self.reverse as f64 >= num_stranded as f64 * STRANDED_FRACTION {
+            Strandedness::Reverse
+        } else {
+            Strandedness::Unstranded
+        }
+    }
 }
 
 pub struct Mapper<'a> {
-    index: &'a Index,
+    shards: Vec<&'a Index>,
+    /// Global id of the first sequence of each shard
+    first_seq_ids: Vec<usize>,
+    /// Largest number of hits that a match can have at one reference position,
+    /// which is more than one where the index has auxiliary sequences
+    max_copies: usize,
     library_type: LibraryType,
+    max_fragment_len: usize,
+    seeding: Seeding,
     seed_min_len: usize,
     seed_max_hits: usize,
     sparsity: usize,
+    minimizer_window: usize,
+    hash_func: HashFunc,
+    seed_max_mismatches: usize,
+    seed_core_len: usize,
+    reseed: bool,
+    reseed_min_len: usize,
+    max_sampled_hits: usize,
+    chain_max_gap: usize,
+    chain_bandwidth: usize,
+    max_intron_len: usize,
+    align_params: AlignParams,
+    detect_chimeras: bool,
+    detect_back_splices: bool,
+    chimeric_min_len: usize,
 }
 
 impl Mapper<'_> {
-    pub fn map(&self, query: &[u8]) -> Vec<Mapping> {
-        if query.len() < self.seed_min_len {
-            // TODO
-            return Vec::new();
+    pub fn map(&self, query: &[u8]) -> MapResult {
+        self.map_batch(&[query]).pop().unwrap()
+    }
+
+    /// Maps a batch of reads, searching the seeds of all of them together to
+    /// hide the memory latency of the index lookups.
+    pub fn map_batch(&self, queries: &[&[u8]]) -> Vec<MapResult> {
+        self.map_mate_batch(queries, true)
+    }
+
+    /// Maps a batch of reads as [`Self::map_batch`] does, all of which are read 1
+    /// or all read 2 of their pairs, such as the biological reads of barcoded pairs.
+    pub fn map_mate_batch(&self, queries: &[&[u8]], is_read1: bool) -> Vec<MapResult> {
+        let reads: Vec<_> = queries.iter().map(|&query| (query, is_read1)).collect();
+        self.map_reads(&reads)
+    }
+
+    pub fn map_pair(&self, read1: &[u8], read2: &[u8]) -> PairMapResult {
+        self.map_pair_batch(&[(read1, read2)]).pop().unwrap()
+    }
+
+    /// Maps a batch of pairs as [`Self::map_batch`] does, and moves the best scoring
+    /// concordant pair of mappings, if any, to the front of the results of both mates.
+    pub fn map_pair_batch(&self, pairs: &[(&[u8], &[u8])]) -> Vec<PairMapResult> {
+        let reads: Vec<_> = pairs
+            .iter()
+            .flat_map(|&(read1, read2)| [(read1, true), (read2, false)])
+            .collect();
+
+        let mut results = self.map_reads(&reads).into_iter();
+        let mut pair_results = Vec::with_capacity(pairs.len());
+        while let (Some(result1), Some(result2)) = (results.next(), results.next()) {
+            pair_results.push(self.pair_results(result1, result2));
         }
+        pair_results
+    }
 
-        let rc_query = sequence::reverse_complement(query);
+    fn pair_results(&self, mut result1: MapResult, mut result2: MapResult) -> PairMapResult {
+        let mut best: Option<(i32, usize, usize)> = None;
+        for (i, mapping1) in result1.mappings.iter().enumerate() {
+            for (j, mapping2) in result2.mappings.iter().enumerate() {
+                let score = mapping1.score + mapping2.score;
+                if self.is_concordant(mapping1, mapping2)
+                    && !matches!(best, Some((best_score, _, _)) if best_score >= score)
+                {
+                    best = Some((score, i, j));
+                }
+            }
+        }
 
-        let ref_to_anchors = self.search_anchors(query, &rc_query, true);
-        if ref_to_anchors.is_empty() {
-            return Vec::new();
+        let is_concordant = match best {
+            Some((_, i, j)) => {
+                for (result, index) in [(&mut result1, i), (&mut result2, j)] {
+                    if index > 0 {
+                        // Supplementary alignments complement the former best mapping
+                        result.mappings.swap(0, index);
+                        result.supplementary.clear();
+                    }
+                }
+                true
+            }
+            None => false,
+        };
+
+        PairMapResult {
+            read1: result1,
+            read2: result2,
+            is_concordant,
         }
+    }
 
-        let mut mappings = Vec::new();
-        for ((seq_id, strand), anchors) in ref_to_anchors {
-            mappings.push(Mapping {
-                seq_id,
-                pos: anchors[0].ref_pos,
-                strand,
-                score: 0,
-            });
+    /// Checks that mappings of read 1 and read 2 lie on the same sequence within
+    /// `max_fragment_len` bases and are oriented as the library type requires.
+    fn is_concordant(&self, mapping1: &Mapping, mapping2: &Mapping) -> bool {
+        if mapping1.seq_id != mapping2.seq_id {
+            return false;
+        }
+        let start = mapping1.pos.min(mapping2.pos);
+        let end =
+            (mapping1.pos + mapping1.cigar.ref_len()).max(mapping2.pos + mapping2.cigar.ref_len());
+        if end - start > self.max_fragment_len {
+            return false;
         }
 
-        mappings
+        let orientation = match self.library_type.orientation {
+            Some(orientation) => orientation,
+            None => return true,
+        };
+        if orientation == MateOrientation::Matching {
+            return mapping1.strand == mapping2.strand;
+        }
+        if mapping1.strand == mapping2.strand {
+            return false;
+        }
+        let (forward, reverse) = if mapping1.strand.is_forward() {
+            (mapping1, mapping2)
+        } else {
+            (mapping2, mapping1)
+        };
+        match orientation {
+            MateOrientation::Inward => forward.pos <= reverse.pos,
+            _ => reverse.pos <= forward.pos,
+        }
     }
 
-    fn search_anchors(
+    /// Maps reads given with whether each is read 1 of its pair.
+    fn map_reads(&self, reads: &[(&[u8], bool)]) -> Vec<MapResult> {
+        let rc_queries: Vec<_> = reads
+            .iter()
+            .map(|(query, _)| sequence::reverse_complement(query))
+            .collect();
+        let mut seed_stats = vec![SeedStats::default(); reads.len()];
+        let ref_to_anchors = self.search_anchors(reads, &rc_queries, &mut seed_stats);
+
+        reads
+            .iter()
+            .zip(&rc_queries)
+            .zip(ref_to_anchors)
+            .zip(seed_stats)
+            .map(|((((query, _), rc_query), ref_to_anchors), seed_stats)| {
+                self.map_anchors(query, rc_query, ref_to_anchors, seed_stats)
+            })
+            .collect()
+    }
+
+    fn map_anchors(
         &self,
         query: &[u8],
         rc_query: &[u8],
-        is_read1: bool,
-    ) -> FxHashMap<(SequenceId, Strand), Vec<Anchor>> {
-        let mut ref_to_anchors: FxHashMap<(SequenceId, Strand), Vec<Anchor>> = FxHashMap::default();
-
-        let mut seed = |query: &[u8], strand| {
-            for seed_pos in (0..=(query.len() - self.seed_min_len)).step_by(self.sparsity) {
-                let result = self.index.sa.extension_search(
-                    &self.index.seq,
-                    &query[seed_pos..],
-                    self.seed_min_len,
-                    self.seed_max_hits,
+        ref_to_anchors: FxHashMap<(SequenceId, Strand), Vec<Anchor>>,
+        seed_stats: SeedStats,
+    ) -> MapResult {
+        if query.len() < self.seed_min_len {
+            return MapResult::unmapped(Outcome::TooShort, seed_stats);
+        }
+        if ref_to_anchors.is_empty() {
+            let outcome = if seed_stats.num_tried == 0 {
+                Outcome::NoSeeds
+            } else if seed_stats.num_over_cap > 0 {
+                Outcome::Repetitive
+            } else {
+                Outcome::NoHits
+            };
+            return MapResult::unmapped(outcome, seed_stats);
+        }
+
+        let groups: Vec<_> = ref_to_anchors
+            .into_iter()
+            .map(|((seq_id, strand), anchors)| {
+                let chains = chain::chain(
+                    &anchors,
+                    self.chain_max_gap,
+                    self.chain_bandwidth,
+                    self.max_intron_len,
+                    &self.align_params.scoring,
                 );
-                if let Some((range, len)) = result {
-                    for i in range {
-                        let pos = self.index.sa.index_to_pos(i);
-                        let id = self.index.seq_id_from_pos(pos);
-
-                        ref_to_anchors
-                            .entry((id, strand))
-                            .and_modify(|anchors| {
-                                anchors.push(Anchor {
-                                    query_pos: seed_pos,
-                                    ref_pos: pos,
-                                    len,
-                                })
-                            })
-                            .or_insert_with(|| {
-                                vec![Anchor {
-                                    query_pos: seed_pos,
-                                    ref_pos: pos,
-                                    len,
-                                }]
-                            });
-                    }
+                (seq_id, strand, chains)
+            })
+            .collect();
+
+        let mut mappings: Vec<_> = groups
+            .iter()
+            .enumerate()
+            .map(|(i, (seq_id, strand, chains))| {
+                let mapping = self.align_chain(
+                    *seq_id,
+                    *strand,
+                    query,
+                    rc_query,
+                    &chains[0],
+                    &self.align_params,
+                );
+                (i, mapping)
+            })
+            .collect();
+        mappings.sort_by_key(|(_, mapping)| Reverse(mapping.score));
+        // As without alternative alleles, only the best alignment to each sequence
+        // and strand is kept when the read also aligns to the alleles on it
+        let mut aligned = FxHashSet::default();
+        mappings.retain(|(_, mapping)| aligned.insert((mapping.seq_id, mapping.strand)));
+
+        // As in selective alignment, a read is discarded if a decoy matches it
+        // strictly better than any other sequence, and decoys are never reported
+        let (decoys, targets): (Vec<_>, Vec<_>) = mappings
+            .into_iter()
+            .partition(|(_, mapping)| self.is_decoy(mapping.seq_id));
+        mappings = targets;
+        match (decoys.first(), mappings.first()) {
+            (Some((_, decoy)), Some((_, target))) if decoy.score <= target.score => {}
+            (Some(_), _) => return MapResult::unmapped(Outcome::Decoy, seed_stats),
+            (None, _) => {}
+        }
+
+        let primary = mappings[0].0;
+        let best_coverage = coverage(&groups[primary].2[0].anchors);
+
+        let mut supplementary = Vec::new();
+        if self.detect_chimeras || self.detect_back_splices {
+            if let Some((group, chain_idx)) = self.split_partner(&groups, primary, query.len()) {
+                // Both parts are aligned locally so that each soft-clips the other
+                let params = AlignParams {
+                    mode: AlignmentMode::Local,
+                    ..self.align_params
+                };
+                let (seq_id, strand, chains) = &groups[primary];
+                mappings[0].1 =
+                    self.align_chain(*seq_id, *strand, query, rc_query, &chains[0], &params);
+                let (seq_id, strand, chains) = &groups[group];
+                supplementary.push(self.align_chain(
+                    *seq_id,
+                    *strand,
+                    query,
+                    rc_query,
+                    &chains[chain_idx],
+                    &params,
+                ));
+                if chain_idx == 0 {
+                    mappings.retain(|(i, _)| *i != group);
+                }
+            }
+        }
+
+        if supplementary.is_empty() && self.align_params.long_reads {
+            // Long reads are split where their alignment drops, as across structural
+            // variants, and the parts aligning other read bases are supplementary
+            let (seq_id, strand, chains) = &groups[primary];
+            let parts = self.align_chain_parts(*seq_id, *strand, query, rc_query, &chains[0]);
+            let mut covered = vec![aligned_range(&mappings[0].1.cigar)];
+            for part in parts.into_iter().skip(1) {
+                let range = aligned_range(&part.cigar);
+                if uncovered_len(&range, &covered) >= self.chimeric_min_len {
+                    covered.push(range);
+                    supplementary.push(part);
                 }
             }
+        }
+
+        MapResult {
+            mappings: mappings.into_iter().map(|(_, mapping)| mapping).collect(),
+            supplementary,
+            outcome: Outcome::Mapped,
+            seed_stats,
+            best_coverage,
+        }
+    }
+
+    fn align_chain(
+        &self,
+        seq_id: SequenceId,
+        strand: Strand,
+        query: &[u8],
+        rc_query: &[u8],
+        chain: &Chain,
+        params: &AlignParams,
+    ) -> Mapping {
+        let query = if strand.is_forward() { query } else { rc_query };
+        let (index, shard_seq_id) = self.locate(seq_id);
+        let seq_range = index.seq_range(shard_seq_id);
+        let alignment = align::align(&index.seq, seq_range, query, &chain.anchors, params);
+        self.mapping(seq_id, strand, query, alignment)
+    }
+
+    /// Aligns the parts of `chain` split where the alignment drops, best first.
+    fn align_chain_parts(
+        &self,
+        seq_id: SequenceId,
+        strand: Strand,
+        query: &[u8],
+        rc_query: &[u8],
+        chain: &Chain,
+    ) -> Vec<Mapping> {
+        let query = if strand.is_forward() { query } else { rc_query };
+        let (index, shard_seq_id) = self.locate(seq_id);
+        let seq_range = index.seq_range(shard_seq_id);
+        align::align_parts(
+            &index.seq,
+            seq_range,
+            query,
+            &chain.anchors,
+            &self.align_params,
+        )
+        .into_iter()
+        .map(|alignment| self.mapping(seq_id, strand, query, alignment))
+        .collect()
+    }
+
+    /// Turns an alignment of `query`, oriented as `strand`, to the sequence `seq_id`
+    /// into a mapping.
+    fn mapping(
+        &self,
+        seq_id: SequenceId,
+        strand: Strand,
+        query: &[u8],
+        alignment: Alignment,
+    ) -> Mapping {
+        let (index, shard_seq_id) = self.locate(seq_id);
+        let mut pos = alignment.ref_start - index.seq_range(shard_seq_id).start;
+
+        // Alignments to auxiliary sequences are moved onto their reference sequences,
+        // keeping the score against the allele they match
+        let (seq_id, ref_start) = match index.lift(shard_seq_id) {
+            Some(lift) => {
+                pos += lift.start;
+                let ref_start = index.seq_range(lift.seq_id).start + pos;
+                (
+                    SequenceId(seq_id.0 - shard_seq_id.0 + lift.seq_id.0),
+                    ref_start,
+                )
+            }
+            None => (seq_id, alignment.ref_start),
         };
+        let edit_distance = align::edit_distance(&index.seq, ref_start, query, &alignment.cigar);
+
+        Mapping {
+            seq_id,
+            pos,
+            strand,
+            score: alignment.score,
+            cigar: alignment.cigar,
+            edit_distance,
+        }
+    }
+
+    /// Returns the shard holding the sequence `seq_id` and its id in the shard.
+    fn locate(&self, seq_id: SequenceId) -> (&Index, SequenceId) {
+        let (shard, seq_id) = index::locate(&self.first_seq_ids, seq_id);
+        (self.shards[shard], seq_id)
+    }
+
+    fn is_decoy(&self, seq_id: SequenceId) -> bool {
+        let (index, seq_id) = self.locate(seq_id);
+        index.is_decoy(seq_id)
+    }
+
+    /// Finds the highest scoring chain, other than the best chain of `groups[primary]`,
+    /// that covers at least `chimeric_min_len` read bases not covered by it.
+    ///
+    /// Chains on the same sequence and strand that are out of order with the best
+    /// chain are back-splice partners, other chains are chimeric partners.
+    fn split_partner(
+        &self,
+        groups: &[(SequenceId, Strand, Vec<Chain>)],
+        primary: usize,
+        query_len: usize,
+    ) -> Option<(usize, usize)> {
+        let (_, strand, chains) = &groups[primary];
+        let primary_span = read_span(&chains[0], *strand, query_len);
+
+        let mut best: Option<(i32, usize, usize)> = None;
+        for (i, (seq_id, strand, chains)) in groups.iter().enumerate() {
+            if self.is_decoy(*seq_id) {
+                continue;
+            }
+            for (j, chain) in chains.iter().enumerate() {
+                if i == primary && j == 0 {
+                    continue;
+                }
+                let is_enabled = if i == primary && is_back_spliced(&chains[0], chain) {
+                    self.detect_back_splices
+                } else {
+                    self.detect_chimeras
+                };
+                if !is_enabled {
+                    continue;
+                }
+                let span = read_span(chain, *strand, query_len);
+                let overlap = span
+                    .end
+                    .min(primary_span.end)
+                    .saturating_sub(span.start.max(primary_span.start));
+                let novel = span.len() - overlap;
+                if novel < self.chimeric_min_len || overlap >= novel {
+                    continue;
+                }
+                if !matches!(best, Some((score, _, _)) if score >= chain.score) {
+                    best = Some((chain.score, i, j));
+                }
+            }
+        }
+        best.map(|(_, i, j)| (i, j))
+    }
 
-        match (self.library_type, is_read1) {
-            (LibraryType::Unstranded, _) => {
-                seed(query, Strand::Forward);
-                seed(rc_query, Strand::Reverse);
+    /// Finds the anchors of each read on the strands allowed by the library type.
+    ///
+    /// The seeds of all reads are searched in one batch.
+    fn search_anchors(
+        &self,
+        reads: &[(&[u8], bool)],
+        rc_queries: &[Vec<u8>],
+        stats: &mut [SeedStats],
+    ) -> Vec<FxHashMap<(SequenceId, Strand), Vec<Anchor>>> {
+        let mut oriented = Vec::new();
+        for (i, &(query, is_read1)) in reads.iter().enumerate() {
+            if query.len() < self.seed_min_len {
+                continue;
             }
-            (LibraryType::FirstStrand, false) | (LibraryType::SecondStrand, true) => {
-                seed(query, Strand::Forward);
+            match self.library_type.read_strand(is_read1) {
+                None => oriented.extend([(i, Strand::Forward), (i, Strand::Reverse)]),
+                Some(strand) => oriented.push((i, strand)),
             }
-            (LibraryType::SecondStrand, false) | (LibraryType::FirstStrand, true) => {
-                seed(rc_query, Strand::Reverse);
+        }
+        let queries: Vec<_> = oriented
+            .iter()
+            .map(|&(i, strand)| match strand {
+                Strand::Forward => reads[i].0,
+                Strand::Reverse => &rc_queries[i][..],
+            })
+            .collect();
+
+        let mut oriented_stats = vec![SeedStats::default(); queries.len()];
+        let seeds = self.seeds(&queries, &mut oriented_stats);
+
+        let mut ref_to_anchors: Vec<FxHashMap<(SequenceId, Strand), Vec<Anchor>>> =
+            reads.iter().map(|_| FxHashMap::default()).collect();
+        for ((&(i, strand), seeds), oriented_stats) in
+            oriented.iter().zip(seeds).zip(oriented_stats)
+        {
+            stats[i].num_tried += oriented_stats.num_tried;
+            stats[i].num_with_hits += oriented_stats.num_with_hits;
+            stats[i].num_over_cap += oriented_stats.num_over_cap;
+
+            for seed in seeds {
+                let index = self.shards[seed.shard];
+                let first_seq_id = self.first_seq_ids[seed.shard];
+                for j in seed.hits() {
+                    let pos = index.sa.index_to_pos(j);
+                    let id = SequenceId(first_seq_id + index.seq_id_from_pos(pos).0);
+
+                    ref_to_anchors[i]
+                        .entry((id, strand))
+                        .or_default()
+                        .push(Anchor {
+                            query_pos: seed.query_pos,
+                            ref_pos: pos,
+                            len: seed.len,
+                        });
+                }
             }
         }
 
+        // Reseeding may find the hits of other seeds starting at the same positions
+        for anchors in ref_to_anchors.iter_mut().flat_map(|map| map.values_mut()) {
+            anchors.sort_unstable_by_key(|anchor| {
+                (anchor.query_pos, anchor.ref_pos, Reverse(anchor.len))
+            });
+            anchors.dedup_by_key(|anchor| (anchor.query_pos, anchor.ref_pos));
+        }
+
         ref_to_anchors
     }
-}
 
-pub struct MapperBuilder<'a> {
-    index: &'a Index,
-    library_type: LibraryType,
-    seed_min_len: usize,
-    seed_max_hits: usize,
-    sparsity: usize,
-}
+    /// Returns the seeds of each of `queries`, which are at least `seed_min_len` long.
+    ///
+    /// The initial searches of all seeds are interleaved with
+    /// [`SuffixArray::extension_search_batch`](crate::index::suffix_array::SuffixArray::extension_search_batch)
+    /// to hide the latency of random accesses to the index.
+    fn seeds(&self, queries: &[&[u8]], stats: &mut [SeedStats]) -> Vec<Vec<Seed>> {
+        let positions: Vec<Vec<usize>> = queries
+            .iter()
+            .map(|query| {
+                let last = query.len() - self.seed_min_len;
+                match self.seeding {
+                    Seeding::Sparse => (0..=last).step_by(self.sparsity).collect(),
+                    Seeding::Smem => (0..=last).collect(),
+                    Seeding::Minimizer => minimizers(
+                        query,
+                        self.seed_min_len,
+                        self.minimizer_window,
+                        self.hash_func,
+                    ),
+                }
+            })
+            .collect();
 
-impl<'a> MapperBuilder<'a> {
-    pub fn new(index: &'a Index) -> Self {
-        Self {
-            index,
-            library_type: LibraryType::Unstranded,
-            seed_min_len: 31,
-            seed_max_hits: 10,
-            sparsity: 1,
-        }
+        let suffixes: Vec<_> = queries
+            .iter()
+            .zip(&positions)
+            .flat_map(|(query, positions)| positions.iter().map(move |&pos| &query[pos..]))
+            .collect();
+        // Maximal exact matches are extended as far as possible
+        let max_hits = match self.seeding {
+            Seeding::Smem => 0,
+            _ => self.seed_max_hits,
+        };
+
+        // Each shard is searched separately, then the searches of each suffix are
+        // combined so that the hits are capped in total
+        let shard_max_hits = max_hits.saturating_mul(self.max_copies);
+        let mut extensions: Vec<_> = self
+            .shards
+            .iter()
+            .map(|index| {
+                index
+                    .sa
+                    .extension_search_batch(
+                        &index.seq,
+                        &suffixes,
+                        self.seed_min_len,
+                        shard_max_hits,
+                    )
+                    .into_iter()
+            })
+            .collect();
+
+        queries
+            .iter()
+            .zip(&positions)
+            .zip(stats.iter_mut())
+            .map(|((query, positions), stats)| {
+                let matches: Vec<_> = positions
+                    .iter()
+                    .map(|&pos| {
+                        let shard_extensions = extensions
+                            .iter_mut()
+                            .map(|extensions| extensions.next().unwrap())
+                            .collect();
+                        self.merge_extensions(&query[pos..], shard_extensions, max_hits)
+                    })
+                    .collect();
+                match self.seeding {
+                    Seeding::Smem => self.smems(query, matches, stats),
+                    _ => self.extension_seeds(query, positions, matches, stats),
+                }
+            })
+            .collect()
     }
 
-    pub fn library_type(&mut self, library_type: LibraryType) -> &mut Self {
-        self.library_type = library_type;
-        self
+    /// Combines the extension searches of `query` in each shard into the search of
+    /// one index made of all shards, which has hits at `max_hits` reference positions
+    /// at most, as counted by [`Self::num_loci`].
+    ///
+    /// Each shard only extended its match until it had few enough hits by itself,
+    /// so the matches are narrowed to the longest of them, then together while
+    /// they have too many hits.
+    fn merge_extensions(
+        &self,
+        query: &[u8],
+        extensions: Vec<Extension>,
+        max_hits: usize,
+    ) -> Matches {
+        let mut len = 0;
+        let mut searches = Vec::new();
+        for (shard, extension) in extensions.into_iter().enumerate() {
+            if let Extension::Hits(range, depth) | Extension::TooManyHits(range, depth) = extension
+            {
+                len = len.max(depth);
+                searches.push((shard, range, depth));
+            }
+        }
+
+        let mut ranges = Vec::new();
+        for (shard, mut range, mut depth) in searches {
+            while depth < len && !range.is_empty() {
+                range = self.narrow(shard, query, depth, range);
+                depth += 1;
+            }
+            if !range.is_empty() {
+                ranges.push((shard, range));
+            }
+        }
+        if ranges.is_empty() {
+            return Matches::NotFound;
+        }
+
+        while len < query.len() && self.num_loci(&ranges, max_hits) > max_hits {
+            let narrowed: Vec<_> = ranges
+                .iter()
+                .map(|(shard, range)| (*shard, self.narrow(*shard, query, len, range.clone())))
+                .filter(|(_, range)| !range.is_empty())
+                .collect();
+            if narrowed.is_empty() {
+                break;
+            }
+            ranges = narrowed;
+            len += 1;
+        }
+
+        if self.num_loci(&ranges, max_hits) > max_hits {
+            Matches::TooManyHits(ranges, len)
+        } else {
+            Matches::Hits(ranges, len)
+        }
     }
 
-    pub fn seed_min_len(&mut self, seed_min_len: usize) -> &mut Self {
-        self.seed_min_len = seed_min_len;
-        self
+    /// Narrows `range` of the suffix array of `shard` to the suffixes also matching
+    /// `query[depth]`.
+    fn narrow(
+        &self,
+        shard: usize,
+        query: &[u8],
+        depth: usize,
+        range: Range<usize>,
+    ) -> Range<usize> {
+        let index = self.shards[shard];
+        index.sa.narrow(&index.seq, query, depth, range)
     }
 
-    pub fn seed_max_hits(&mut self, seed_max_hits: usize) -> &mut Self {
-        self.seed_max_hits = seed_max_hits;
-        self
+    /// Returns the number of reference positions of the hits in `ranges`, counting
+    /// the hits on auxiliary sequences at the positions they are lifted to, so that
+    /// alternative alleles do not make a match repetitive. Counting stops once the
+    /// number exceeds `max_hits`.
+    fn num_loci(&self, ranges: &[(usize, Range<usize>)], max_hits: usize) -> usize {
+        let num_hits = num_hits(ranges);
+        if num_hits <= max_hits || self.max_copies == 1 {
+            return num_hits;
+        }
+        // Every reference position has at most `max_copies` hits, so more than
+        // `max_hits` positions are found among `max_copies * max_hits + 1` hits
+        let mut loci = FxHashSet::default();
+        for (shard, range) in ranges {
+            let index = self.shards[*shard];
+            for i in range.clone() {
+                loci.insert((*shard, index.lift_pos(index.sa.index_to_pos(i))));
+                if loci.len() > max_hits {
+                    return loci.len();
+                }
+            }
+        }
+        loci.len()
     }
 
-    pub fn sparsity(&mut self, sparsity: usize) -> &mut Self {
-        self.sparsity = sparsity;
-        self
+    /// Turns the results of extension searches from `positions` into seeds,
+    /// falling back to reseeding or mismatch seeds where they failed.
+    fn extension_seeds(
+        &self,
+        query: &[u8],
+        positions: &[usize],
+        matches: Vec<Matches>,
+        stats: &mut SeedStats,
+    ) -> Vec<Seed> {
+        let mut seeds = Vec::new();
+        let mut repeat_end = 0;
+        for (&query_pos, matches) in positions.iter().zip(matches) {
+            let num_seeds = seeds.len();
+            match matches {
+                Matches::Hits(ranges, len) => seeds.extend(hit_seeds(query_pos, ranges, len)),
+                Matches::TooManyHits(ranges, len) => {
+                    stats.num_over_cap += 1;
+                    seeds.extend(self.repetitive_seeds(
+                        query,
+                        query_pos,
+                        ranges,
+                        len,
+                        &mut repeat_end,
+                    ))
+                }
+                Matches::NotFound => {
+                    if self.seed_max_mismatches > 0 {
+                        seeds.extend(self.mismatch_seeds(query, query_pos));
+                    }
+                }
+            }
+
+            stats.num_tried += 1;
+            if seeds.len() > num_seeds {
+                stats.num_with_hits += 1;
+            }
+        }
+        seeds
     }
 
-    pub fn build(&self) -> Mapper<'a> {
-        Mapper {
-            index: self.index,
-            library_type: self.library_type,
-            seed_min_len: self.seed_min_len,
+    /// Returns the seeds standing for the repetitive match `query[query_pos..query_pos + len]`,
+    /// unless it ends no further than `repeat_end`, the end of the repetitive
+    /// matches already handled, which it is then contained in.
+    fn repetitive_seeds(
+        &self,
+        query: &[u8],
+        query_pos: usize,
+        ranges: Vec<(usize, Range<usize>)>,
+        len: usize,
+        repeat_end: &mut usize,
+    ) -> Vec<Seed> {
+        let region = query_pos..query_pos + len;
+        if region.end <= *repeat_end {
+            return Vec::new();
+        }
+        let reseed_start = (*repeat_end).max(region.start + 1);
+        *repeat_end = region.end;
+
+        if self.reseed {
+            let seeds = self.reseed(query, reseed_start..region.end);
+            if !seeds.is_empty() {
+                return seeds;
+            }
+        }
+
+        if self.max_sampled_hits == 0 {
+            return Vec::new();
+        }
+        // The generator is seeded by the read, so that its mappings do not depend on
+        // which reads it is mapped with
+        let rng_seed = (HashFunc::XxHash.hash(query) as u64) << 32 | query_pos as u64;
+        let sampled = sample(0..num_hits(&ranges), self.max_sampled_hits, rng_seed);
+
+        // The hits are sampled from the ranges of all shards in turn
+        let mut seeds = Vec::new();
+        let mut offset = 0;
+        for (shard, range) in ranges {
+            let end = offset + range.len();
+            let sample: Vec<_> = sampled
+                .iter()
+                .filter(|&&i| offset <= i && i < end)
+                .map(|&i| range.start + i - offset)
+                .collect();
+            if !sample.is_empty() {
+                seeds.push(Seed {
+                    shard,
+                    query_pos,
+                    range,
+                    len,
+                    sample: Some(sample),
+                });
+            }
+            offset = end;
+        }
+        seeds
+    }
+
+    /// Retries with shorter, overlapping seeds starting at `positions` inside a
+    /// repetitive match. Copies of the repeat that diverge from the read where the
+    /// match ends may still match a later part of the read beyond it.
+    fn reseed(&self, query: &[u8], positions: Range<usize>) -> Vec<Seed> {
+        let min_len = self.reseed_min_len;
+        let step = (min_len / 2).max(1);
+        // Seeds start at multiples of `step`, wherever the match starts
+        let start = positions.start + (step - positions.start % step) % step;
+        let end = positions.end.min((query.len() + 1).saturating_sub(min_len));
+
+        let mut seeds = Vec::new();
+        for pos in (start..end).step_by(step) {
+            let extensions = self
+                .shards
+                .iter()
+                .map(|index| {
+                    index.sa.extension_search(
+                        &index.seq,
+                        &query[pos..],
+                        min_len,
+                        self.seed_max_hits.saturating_mul(self.max_copies),
+                    )
+                })
+                .collect();
+            if let Matches::Hits(ranges, len) =
+                self.merge_extensions(&query[pos..], extensions, self.seed_max_hits)
+            {
+                seeds.extend(hit_seeds(pos, ranges, len));
+            }
+        }
+        seeds
+    }
+
+    fn mismatch_seeds(&self, query: &[u8], query_pos: usize) -> Vec<Seed> {
+        let mut ranges = Vec::new();
+        for (shard, index) in self.shards.iter().enumerate() {
+            let shard_ranges = index.sa.mismatch_search(
+                &index.seq,
+                &query[query_pos..],
+                self.seed_min_len,
+                self.seed_core_len,
+                self.seed_max_mismatches,
+            );
+            ranges.extend(shard_ranges.into_iter().map(|range| (shard, range)));
+        }
+
+        if self.num_loci(&ranges, self.seed_max_hits) > self.seed_max_hits {
+            return Vec::new();
+        }
+        hit_seeds(query_pos, ranges, self.seed_min_len).collect()
+    }
+
+    /// Selects the maximal matches among `longest_matches`, the longest match
+    /// starting at each query position.
+    fn smems(
+        &self,
+        query: &[u8],
+        longest_matches: Vec<Matches>,
+        stats: &mut SeedStats,
+    ) -> Vec<Seed> {
+        let mut seeds = Vec::new();
+        let mut repeat_end = 0;
+
+        // The end of the longest match starting at each position never decreases,
+        // so a match is contained in an earlier one iff it ends no further right.
+        let mut prev_end = 0;
+        for (query_pos, matches) in longest_matches.into_iter().enumerate() {
+            if let Matches::Hits(ranges, len) | Matches::TooManyHits(ranges, len) = matches {
+                let end = query_pos + len;
+                if end <= prev_end {
+                    continue;
+                }
+                prev_end = end;
+
+                stats.num_tried += 1;
+                if self.num_loci(&ranges, self.seed_max_hits) <= self.seed_max_hits {
+                    stats.num_with_hits += 1;
+                    seeds.extend(hit_seeds(query_pos, ranges, len));
+                } else {
+                    stats.num_over_cap += 1;
+                    let repetitive_seeds =
+                        self.repetitive_seeds(query, query_pos, ranges, len, &mut repeat_end);
+                    if !repetitive_seeds.is_empty() {
+                        stats.num_with_hits += 1;
+                    }
+                    seeds.extend(repetitive_seeds);
+                }
+            }
+        }
+        seeds
+    }
+}
+
+fn num_hits(ranges: &[(usize, Range<usize>)]) -> usize {
+    ranges.iter().map(|(_, range)| range.len()).sum()
+}
+
+/// Returns the seeds of the hits in `ranges` of the suffix arrays of the shards.
+fn hit_seeds(
+    query_pos: usize,
+    ranges: Vec<(usize, Range<usize>)>,
+    len: usize,
+) -> impl Iterator<Item = Seed> {
+    ranges.into_iter().map(move |(shard, range)| Seed {
+        shard,
+        query_pos,
+        range,
+        len,
+        sample: None,
+    })
+}
+
+/// Range of the read, in its original orientation, spanned by `chain`
+fn read_span(chain: &Chain, strand: Strand, query_len: usize) -> Range<usize> {
+    let first = &chain.anchors[0];
+    let last = chain.anchors.last().unwrap();
+    let (start, end) = (first.query_pos, last.query_pos + last.len);
+    match strand {
+        Strand::Forward => start..end,
+        Strand::Reverse => query_len - end..query_len - start,
+    }
+}
+
+/// Returns whether the later of two chains on the same sequence and strand, in query
+/// order, lies upstream of the other on the reference, as in back-spliced reads.
+fn is_back_spliced(a: &Chain, b: &Chain) -> bool {
+    let (first, second) = if a.anchors[0].query_pos <= b.anchors[0].query_pos {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let last = second.anchors.last().unwrap();
+    last.ref_pos + last.len <= first.anchors[0].ref_pos
+}
+
+/// Range of the query aligned by `cigar`, without its soft clips
+fn aligned_range(cigar: &Cigar) -> Range<usize> {
+    let clip_len = |op: Option<&(CigarOp, usize)>| match op {
+        Some(&(CigarOp::SoftClip, len)) => len,
+        _ => 0,
+    };
+    let ops = cigar.ops();
+    clip_len(ops.first())..cigar.query_len() - clip_len(ops.last())
+}
+
+/// Returns the number of positions of `range` outside all of `covered`.
+fn uncovered_len(range: &Range<usize>, covered: &[Range<usize>]) -> usize {
+    let mut overlaps: Vec<_> = covered
+        .iter()
+        .map(|other| (other.start.max(range.start), other.end.min(range.end)))
+        .filter(|(start, end)| start < end)
+        .collect();
+    overlaps.sort_unstable();
+
+    let mut covered_len = 0;
+    let mut covered_end = range.start;
+    for (start, end) in overlaps {
+        if end > covered_end {
+            covered_len += end - start.max(covered_end);
+            covered_end = end;
+        }
+    }
+    range.len() - covered_len
+}
+
+/// Returns the number of query bases covered by `anchors`.
+fn coverage(anchors: &[Anchor]) -> usize {
+    let mut intervals: Vec<_> = anchors
+        .iter()
+        .map(|anchor| (anchor.query_pos, anchor.query_pos + anchor.len))
+        .collect();
+    intervals.sort_unstable();
+
+    let mut covered = 0;
+    let mut covered_end = 0;
+    for (begin, end) in intervals {
+        if end > covered_end {
+            covered += end - begin.max(covered_end);
+            covered_end = end;
+        }
+    }
+    covered
+}
+
+/// Returns `k` distinct elements of `range` drawn uniformly at random with Floyd's
+/// algorithm, in increasing order, or all of them if it has at most `k`.
+fn sample(range: Range<usize>, k: usize, seed: u64) -> Vec<usize> {
+    if range.len() <= k {
+        return range.collect();
+    }
+
+    let mut state = seed;
+    let mut sampled = FxHashSet::default();
+    for j in range.len() - k..range.len() {
+        let i = (splitmix64(&mut state) % (j as u64 + 1)) as usize;
+        if !sampled.insert(i) {
+            sampled.insert(j);
+        }
+    }
+
+    let mut sample: Vec<_> = sampled.into_iter().map(|i| range.start + i).collect();
+    sample.sort_unstable();
+    sample
+}
+
+/// Advances a SplitMix64 generator and returns its next output.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Returns the start positions of the `(w, k)` minimizers of `seq`,
+/// ignoring k-mers that contain ambiguous bases.
+fn minimizers(seq: &[u8], k: usize, w: usize, hash_func: HashFunc) -> Vec<usize> {
+    if seq.len() < k {
+        return Vec::new();
+    }
+
+    let hashes: Vec<_> = seq
+        .windows(k)
+        .map(|kmer| {
+            if kmer.contains(&sequence::DUMMY_CODE) {
+                None
+            } else {
+                Some(hash_func.hash(kmer))
+            }
+        })
+        .collect();
+
+    let mut positions: Vec<usize> = Vec::new();
+    let mut min: Option<(u32, usize)> = None;
+    for end in 0..hashes.len() {
+        let start = (end + 1).saturating_sub(w);
+        if let Some((_, pos)) = min {
+            if pos < start {
+                // The previous minimizer fell out of the window
+                min = (start..end)
+                    .filter_map(|i| hashes[i].map(|hash| (hash, i)))
+                    .min();
+            }
+        }
+        let candidate = hashes[end].map(|hash| (hash, end));
+        min = min.into_iter().chain(candidate).min();
+
+        if end + 1 >= w.min(hashes.len()) {
+            if let Some((_, pos)) = min {
+                if positions.last() != Some(&pos) {
+                    positions.push(pos);
+                }
+            }
+        }
+    }
+    positions
+}
+
+pub struct MapperBuilder<'a> {
+    shards: Vec<&'a Index>,
+    library_type: LibraryType,
+    max_fragment_len: usize,
+    seeding: Seeding,
+    seed_min_len: usize,
+    seed_max_hits: usize,
+    sparsity: usize,
+    minimizer_window: usize,
+    hash_func: HashFunc,
+    seed_max_mismatches: usize,
+    seed_core_len: usize,
+    reseed: bool,
+    reseed_min_len: usize,
+    max_sampled_hits: usize,
+    chain_max_gap: usize,
+    chain_bandwidth: usize,
+    max_intron_len: usize,
+    scoring: ScoringScheme,
+    alignment_mode: AlignmentMode,
+    xdrop: i32,
+    zdrop: i32,
+    long_reads: bool,
+    detect_chimeras: bool,
+    detect_back_splices: bool,
+    chimeric_min_len: usize,
+}
+
+impl<'a> MapperBuilder<'a> {
+    pub fn new(index: &'a Index) -> Self {
+        Self::from_shards(vec![index])
+    }
+
+    /// Maps against every shard of `index` as against one index made of all of them.
+    /// Mappings are numbered with the global sequence ids of the sharded index.
+    pub fn sharded(index: &'a ShardedIndex) -> Self {
+        Self::from_shards(index.shards.iter().collect())
+    }
+
+    fn from_shards(shards: Vec<&'a Index>) -> Self {
+        Self {
+            shards,
+            library_type: LibraryType::UNSTRANDED,
+            max_fragment_len: 1000,
+            seeding: Seeding::Sparse,
+            seed_min_len: 31,
+            seed_max_hits: 10,
+            sparsity: 1,
+            minimizer_window: 10,
+            hash_func: HashFunc::XxHash,
+            seed_max_mismatches: 0,
+            seed_core_len: 20,
+            reseed: false,
+            reseed_min_len: 20,
+            max_sampled_hits: 0,
+            chain_max_gap: 1000,
+            chain_bandwidth: 50,
+            max_intron_len: 0,
+            scoring: ScoringScheme::default(),
+            alignment_mode: AlignmentMode::EndToEnd,
+            xdrop: 20,
+            zdrop: 100,
+            long_reads: false,
+            detect_chimeras: false,
+            detect_back_splices: false,
+            chimeric_min_len: 20,
+        }
+    }
+
+    /// Sets the seeding, chaining, scoring and alignment options of `preset`.
+    ///
+    /// Every one of these options is overwritten, including those set before, so call
+    /// this first and set the options that override the preset after it.
+    pub fn preset(&mut self, preset: Preset) -> &mut Self {
+        self.seeding = Seeding::Sparse;
+        self.sparsity = 1;
+        self.minimizer_window = 10;
+        self.seed_max_hits = 1000;
+        self.hash_func = HashFunc::XxHash;
+        self.seed_max_mismatches = 0;
+        self.seed_core_len = 20;
+        self.reseed = false;
+        self.reseed_min_len = 20;
+        self.max_sampled_hits = 0;
+        self.chain_max_gap = 1000;
+        self.chain_bandwidth = 50;
+        self.max_intron_len = 0;
+        self.scoring = preset.scoring();
+        self.alignment_mode = AlignmentMode::EndToEnd;
+        self.long_reads = false;
+        self.chimeric_min_len = 20;
+
+        match preset {
+            Preset::SrTranscriptome => {
+                self.seed_min_len = 31;
+            }
+            Preset::SrGenomeSpliced => {
+                self.seed_min_len = 25;
+                self.reseed = true;
+                self.max_intron_len = 500_000;
+            }
+            Preset::LongRead => {
+                self.seeding = Seeding::Minimizer;
+                self.seed_min_len = 15;
+                self.chain_max_gap = 5000;
+                self.chain_bandwidth = 500;
+                self.alignment_mode = AlignmentMode::Local;
+                self.long_reads = true;
+            }
+            Preset::SmallRna => {
+                self.seed_min_len = 16;
+                self.seed_max_hits = 100;
+            }
+        }
+        self
+    }
+
+    pub fn library_type(&mut self, library_type: LibraryType) -> &mut Self {
+        self.library_type = library_type;
+        self
+    }
+
+    /// Sets the longest span of a concordant pair of mappings.
+    pub fn max_fragment_len(&mut self, max_fragment_len: usize) -> &mut Self {
+        self.max_fragment_len = max_fragment_len;
+        self
+    }
+
+    pub fn seeding(&mut self, seeding: Seeding) -> &mut Self {
+        self.seeding = seeding;
+        self
+    }
+
+    pub fn seed_min_len(&mut self, seed_min_len: usize) -> &mut Self {
+        self.seed_min_len = seed_min_len;
+        self
+    }
+
+    pub fn seed_max_hits(&mut self, seed_max_hits: usize) -> &mut Self {
+        self.seed_max_hits = seed_max_hits;
+        self
+    }
+
+    pub fn sparsity(&mut self, sparsity: usize) -> &mut Self {
+        self.sparsity = sparsity;
+        self
+    }
+
+    pub fn minimizer_window(&mut self, minimizer_window: usize) -> &mut Self {
+        self.minimizer_window = minimizer_window;
+        self
+    }
+
+    pub fn hash_func(&mut self, hash_func: HashFunc) -> &mut Self {
+        self.hash_func = hash_func;
+        self
+    }
+
+    /// Allows sparse and minimizer seeds without exact hits to match
+    /// with up to `seed_max_mismatches` substitutions.
+    pub fn seed_max_mismatches(&mut self, seed_max_mismatches: usize) -> &mut Self {
+        self.seed_max_mismatches = seed_max_mismatches;
+        self
+    }
+
+    /// Sets the length of the seed prefix in which no mismatches are allowed.
+    /// It is raised to the length of the prefixes the index buckets suffixes by.
+    pub fn seed_core_len(&mut self, seed_core_len: usize) -> &mut Self {
+        self.seed_core_len = seed_core_len;
+        self
+    }
+
+    /// Retries seeds with more than `seed_max_hits` hits using shorter seeds
+    /// of at least `reseed_min_len` bases starting inside the repetitive match.
+    pub fn reseed(&mut self, reseed: bool) -> &mut Self {
+        self.reseed = reseed;
+        self
+    }
+
+    pub fn reseed_min_len(&mut self, reseed_min_len: usize) -> &mut Self {
+        self.reseed_min_len = reseed_min_len;
+        self
+    }
+
+    /// Keeps a random sample of up to `max_sampled_hits` hits of seeds that remain
+    /// repetitive instead of discarding them. Zero disables sampling.
+    pub fn max_sampled_hits(&mut self, max_sampled_hits: usize) -> &mut Self {
+        self.max_sampled_hits = max_sampled_hits;
+        self
+    }
+
+    pub fn chain_max_gap(&mut self, chain_max_gap: usize) -> &mut Self {
+        self.chain_max_gap = chain_max_gap;
+        self
+    }
+
+    pub fn chain_bandwidth(&mut self, chain_bandwidth: usize) -> &mut Self {
+        self.chain_bandwidth = chain_bandwidth;
+        self
+    }
+
+    /// Allows chained anchors to be separated by reference gaps of up to
+    /// `max_intron_len` bases, for spliced alignment to a genome.
+    pub fn max_intron_len(&mut self, max_intron_len: usize) -> &mut Self {
+        self.max_intron_len = max_intron_len;
+        self
+    }
+
+    /// Sets the scores used to align reads and to compute `Mapping::score`.
+    pub fn scoring(&mut self, scoring: ScoringScheme) -> &mut Self {
+        self.scoring = scoring;
+        self
+    }
+
+    pub fn alignment_mode(&mut self, alignment_mode: AlignmentMode) -> &mut Self {
+        self.alignment_mode = alignment_mode;
+        self
+    }
+
+    /// Sets how far the score of a local extension may drop below its best
+    /// before the extension stops.
+    pub fn xdrop(&mut self, xdrop: i32) -> &mut Self {
+        self.xdrop = xdrop;
+        self
+    }
+
+    /// Sets the penalty above which a gap between chained anchors splits
+    /// a local alignment.
+    pub fn zdrop(&mut self, zdrop: i32) -> &mut Self {
+        self.zdrop = zdrop;
+        self
+    }
+
+    /// Aligns reads as noisy long reads, with indels in place of mismatches where
+    /// they score better, and reports the parts of a read split where its local
+    /// alignment drops below Z-drop as a primary and supplementary alignments.
+    pub fn long_reads(&mut self, long_reads: bool) -> &mut Self {
+        self.long_reads = long_reads;
+        self
+    }
+
+    /// Reports reads whose parts align to different sequences or distant loci
+    /// as a primary and a supplementary alignment.
+    pub fn detect_chimeras(&mut self, detect_chimeras: bool) -> &mut Self {
+        self.detect_chimeras = detect_chimeras;
+        self
+    }
+
+    /// Reports reads whose 3' part aligns upstream of their 5' part on the same
+    /// sequence, as circular RNAs produce, as a primary and a supplementary alignment.
+    pub fn detect_back_splices(&mut self, detect_back_splices: bool) -> &mut Self {
+        self.detect_back_splices = detect_back_splices;
+        self
+    }
+
+    /// Sets the number of read bases a chimeric or back-spliced part must cover beyond the primary alignment.
+    pub fn chimeric_min_len(&mut self, chimeric_min_len: usize) -> &mut Self {
+        self.chimeric_min_len = chimeric_min_len;
+        self
+    }
+
+    pub fn build(&self) -> Mapper<'a> {
+        // No search can match less than the prefixes the suffix arrays are bucketed by
+        let min_search_len = self
+            .shards
+            .iter()
+            .map(|index| index.sa.min_search_len())
+            .max()
+            .unwrap_or(0);
+        let seed_min_len = self.seed_min_len.max(min_search_len);
+
+        Mapper {
+            shards: self.shards.clone(),
+            first_seq_ids: index::first_seq_ids(&self.shards),
+            max_copies: self
+                .shards
+                .iter()
+                .map(|index| index.max_copies())
+                .max()
+                .unwrap_or(1),
+            library_type: self.library_type,
+            max_fragment_len: self.max_fragment_len,
+            seeding: self.seeding,
+            seed_min_len,
             seed_max_hits: self.seed_max_hits,
             sparsity: self.sparsity,
+            minimizer_window: self.minimizer_window,
+            hash_func: self.hash_func,
+            seed_max_mismatches: self.seed_max_mismatches,
+            seed_core_len: self.seed_core_len.clamp(min_search_len, seed_min_len),
+            reseed: self.reseed,
+            reseed_min_len: self.reseed_min_len.clamp(min_search_len, seed_min_len),
+            max_sampled_hits: self.max_sampled_hits,
+            chain_max_gap: self.chain_max_gap,
+            chain_bandwidth: self.chain_bandwidth,
+            max_intron_len: self.max_intron_len,
+            align_params: AlignParams {
+                scoring: self.scoring,
+                mode: self.alignment_mode,
+                xdrop: self.xdrop,
+                zdrop: self.zdrop,
+                long_reads: self.long_reads,
+                bandwidth: self.chain_bandwidth,
+            },
+            detect_chimeras: self.detect_chimeras,
+            detect_back_splices: self.detect_back_splices,
+            chimeric_min_len: self.chimeric_min_len,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        index::{IndexBuilder, Lift},
+        vcf::Variant,
+    };
+
+    const REFERENCE: &[u8] = b"GATTACAGCTTCGAACGTATGCCGTAGGCTAACTGGTCACGATCCTAGTTGCAAGCTGTA";
+
+    fn build_index(seqs: &[&[u8]]) -> Index {
+        let mut fasta: Vec<u8> = Vec::new();
+        for (i, seq) in seqs.iter().enumerate() {
+            fasta.extend(format!(">seq{}\n", i).as_bytes());
+            fasta.extend(*seq);
+            fasta.push(b'\n');
+        }
+        IndexBuilder::new(std::io::Cursor::new(fasta))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn smem_splits_at_mismatch() {
+        let index = build_index(&[REFERENCE]);
+        let mapper = MapperBuilder::new(&index)
+            .seeding(Seeding::Smem)
+            .seed_min_len(12)
+            .build();
+
+        let mut read = REFERENCE[10..50].to_vec();
+        read[20] = if read[20] == b'A' { b'C' } else { b'A' };
+        let read = sequence::encode(&read);
+
+        let got: Vec<_> = mapper
+            .seeds(&[&read], &mut [SeedStats::default()])
+            .remove(0)
+            .into_iter()
+            .map(|seed| {
+                (
+                    seed.query_pos,
+                    seed.len,
+                    index.sa.index_to_pos(seed.range.start),
+                )
+            })
+            .collect();
+        assert_eq!(got, vec![(0, 20, 11), (21, 19, 32)]);
+    }
+
+    #[test]
+    fn batch_search_matches_single_searches() {
+        let mut paralog = REFERENCE[..40].to_vec();
+        paralog.extend(b"TTGACCA");
+        let index = build_index(&[REFERENCE, &paralog]);
+
+        let mut queries: Vec<_> = (0..REFERENCE.len() - 12)
+            .map(|start| sequence::encode(&REFERENCE[start..]))
+            .collect();
+        queries.push(sequence::encode(b"CCCCCCCCCCCCCCCC"));
+        let queries: Vec<_> = queries.iter().map(|query| &query[..]).collect();
+
+        for max_hits in [0, 1, 10] {
+            let batch = index
+                .sa
+                .extension_search_batch(&index.seq, &queries, 12, max_hits);
+            for (query, extension) in queries.iter().zip(batch) {
+                let single = index.sa.extension_search(&index.seq, query, 12, max_hits);
+                assert_eq!(extension, single);
+            }
+        }
+    }
+
+    #[test]
+    fn mismatch_seeds_tolerate_substitutions() {
+        let index = build_index(&[REFERENCE]);
+        let mut builder = MapperBuilder::new(&index);
+        builder.seed_min_len(24).seed_core_len(12);
+
+        let mut read = REFERENCE[5..35].to_vec();
+        read[15] = if read[15] == b'A' { b'C' } else { b'A' };
+        read[20] = if read[20] == b'G' { b'T' } else { b'G' };
+        let read = sequence::encode(&read);
+
+        let seeds = builder
+            .seed_max_mismatches(1)
+            .build()
+            .mismatch_seeds(&read, 0);
+        assert!(seeds.is_empty());
+
+        let seeds = builder
+            .seed_max_mismatches(2)
+            .build()
+            .mismatch_seeds(&read, 0);
+        assert_eq!(seeds.len(), 1);
+        assert_eq!(index.sa.index_to_pos(seeds[0].range.start), 6);
+        assert_eq!(seeds[0].len, 24);
+
+        // Cores shorter than the buckets of the index are lengthened to them
+        let mapper = builder.seed_core_len(4).build();
+        assert_eq!(mapper.seed_core_len, 8);
+        assert_eq!(mapper.mismatch_seeds(&read, 0).len(), 1);
+    }
+
+    #[test]
+    fn reseeding_escapes_repeats() {
+        // The first 30 bases of the read are shared by both paralogs, which diverge
+        // from it right after them, while only the locus matches the rest of the read
+        let mut paralog1 = REFERENCE[..30].to_vec();
+        paralog1.extend(b"TTTTTTTTTTTTTTTTTTTT");
+        let mut paralog2 = REFERENCE[..30].to_vec();
+        paralog2.extend(b"CCCCCCCCCCCCCCCCCCCC");
+        let mut locus = b"CCCCC".to_vec();
+        locus.extend(&REFERENCE[5..]);
+        let index = build_index(&[&paralog1, &paralog2, &locus]);
+
+        let read = sequence::encode(&REFERENCE[..50]);
+        let rc_read = vec![sequence::reverse_complement(&read)];
+        let search_anchors = |mapper: Mapper| {
+            mapper
+                .search_anchors(&[(&read, true)], &rc_read, &mut [SeedStats::default()])
+                .remove(0)
+        };
+        let mut builder = MapperBuilder::new(&index);
+        builder.seed_min_len(30).seed_max_hits(1).sparsity(50);
+
+        let anchors = search_anchors(builder.build());
+        assert!(anchors.is_empty());
+
+        let anchors = search_anchors(builder.reseed(true).reseed_min_len(10).build());
+        assert_eq!(
+            anchors.keys().collect::<Vec<_>>(),
+            vec![&(SequenceId(2), Strand::Forward)]
+        );
+        let query_positions: Vec<_> = anchors[&(SequenceId(2), Strand::Forward)]
+            .iter()
+            .map(|anchor| anchor.query_pos)
+            .collect();
+        assert_eq!(query_positions, vec![5, 10, 15, 20, 25]);
+
+        // Seeds from every position find the repeat, which is reseeded once, and
+        // anchors found both by reseeding and by regular seeds are kept once
+        let anchors = search_anchors(builder.sparsity(1).build());
+        let anchors = &anchors[&(SequenceId(2), Strand::Forward)];
+        let mut unique: Vec<_> = anchors
+            .iter()
+            .map(|anchor| (anchor.query_pos, anchor.ref_pos))
+            .collect();
+        unique.dedup();
+        assert_eq!(unique.len(), anchors.len());
+
+        let anchors = search_anchors(
+            builder
+                .reseed(false)
+                .sparsity(50)
+                .max_sampled_hits(1)
+                .build(),
+        );
+        assert_eq!(anchors.len(), 1);
+        let sampled = anchors.keys().next().unwrap().0;
+        assert!(sampled == SequenceId(0) || sampled == SequenceId(1));
+    }
+
+    #[test]
+    fn sampling_is_uniform() {
+        let mut counts = [0; 10];
+        for seed in 0..1000 {
+            let sample = sample(100..110, 3, seed);
+            assert_eq!(sample.len(), 3);
+            assert!(sample.windows(2).all(|w| w[0] < w[1]));
+            for i in sample {
+                counts[i - 100] += 1;
+            }
+        }
+        // Each element is expected to be drawn 300 times
+        assert!(counts.iter().all(|&count| (200..400).contains(&count)));
+        assert_eq!(sample(3..5, 3, 0), vec![3, 4]);
+    }
+
+    #[test]
+    fn map_reports_outcome() {
+        let index = build_index(&[REFERENCE]);
+        let mapper = MapperBuilder::new(&index).seed_min_len(12).build();
+
+        let result = mapper.map(&sequence::encode(b"GATTACA"));
+        assert_eq!(result.outcome, Outcome::TooShort);
+
+        let result = mapper.map(&sequence::encode(b"CCCCCCCCCCCCCCCCCCCC"));
+        assert_eq!(result.outcome, Outcome::NoHits);
+        assert_eq!(result.seed_stats.num_tried, 9 * 2);
+
+        let result = mapper.map(&sequence::encode(&REFERENCE[20..40]));
+        assert_eq!(result.outcome, Outcome::Mapped);
+        assert_eq!(result.mappings[0].pos, 20);
+        assert_eq!(result.best_coverage, 20);
+    }
+
+    #[test]
+    fn local_alignment_soft_clips_adapter() {
+        let index = build_index(&[REFERENCE]);
+        let mut builder = MapperBuilder::new(&index);
+        builder.seed_min_len(12);
+
+        let mut read = REFERENCE[10..40].to_vec();
+        read.extend(b"CTGTCTCTTATACACATCT");
+        let read = sequence::encode(&read);
+
+        let result = builder.build().map(&read);
+        assert_eq!(result.mappings[0].pos, 10);
+        assert_eq!(result.mappings[0].cigar.to_string(), "49M");
+
+        let result = builder
+            .alignment_mode(AlignmentMode::Local)
+            .build()
+            .map(&read);
+        let mapping = &result.mappings[0];
+        assert_eq!(mapping.pos, 10);
+        assert!(mapping.cigar.to_string().ends_with('S'));
+        assert_eq!(mapping.cigar.query_len(), read.len());
+    }
+
+    #[test]
+    fn scoring_scheme_sets_mapping_score() {
+        let index = build_index(&[REFERENCE]);
+        let mut builder = MapperBuilder::new(&index);
+        builder.seed_min_len(12);
+
+        let mut read = REFERENCE[10..40].to_vec();
+        read[2] = b'N';
+        read[27] = if read[27] == b'A' { b'C' } else { b'A' };
+        let read = sequence::encode(&read);
+
+        let result = builder.build().map(&read);
+        assert_eq!(result.mappings[0].score, 28 * 2 - 4 - 4);
+
+        let result = builder
+            .scoring(ScoringScheme {
+                match_score: 1,
+                mismatch_penalty: 3,
+                n_penalty: Some(1),
+                ..ScoringScheme::default()
+            })
+            .build()
+            .map(&read);
+        assert_eq!(result.mappings[0].score, 28 - 3 - 1);
+    }
+
+    #[test]
+    fn spliced_read_chains_across_intron() {
+        let other: &[u8] = b"TCGGATACCTTAGCAGTCAACGGTTCAGACCATTGGACTCGATAAGCCTGGTACTAGC";
+        let mut genome = REFERENCE[..40].to_vec();
+        genome.extend([b'A'; 200]);
+        genome.extend(&other[..40]);
+        let index = build_index(&[&genome]);
+
+        let mut read = REFERENCE[..40].to_vec();
+        read.extend(&other[..40]);
+        let read = sequence::encode(&read);
+
+        let result = MapperBuilder::new(&index)
+            .preset(Preset::SrGenomeSpliced)
+            .build()
+            .map(&read);
+        let mapping = &result.mappings[0];
+        assert_eq!(mapping.pos, 0);
+        assert_eq!(mapping.cigar.to_string(), "40M200N40M");
+        assert_eq!(mapping.score, 80 * 2 - 8);
+    }
+
+    fn random_seq(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| b"ACGT"[(splitmix64(&mut state) % 4) as usize])
+            .collect()
+    }
+
+    #[test]
+    fn long_read_aligns_across_errors_and_intron() {
+        let genome = random_seq(3000, 1);
+        let index = build_index(&[&genome]);
+
+        // Exons of 800 bases around an intron of 300 bases, with a substitution,
+        // an insertion or a deletion every 40 bases away from their ends
+        let mut read = Vec::new();
+        for exon in [&genome[200..1000], &genome[1300..2100]] {
+            for (i, &base) in exon.iter().enumerate() {
+                if i < 60 || i >= exon.len() - 60 || i % 40 != 0 {
+                    read.push(base);
+                    continue;
+                }
+                match i / 40 % 3 {
+                    0 => read.push(if base == b'A' { b'C' } else { b'A' }),
+                    1 => read.extend([base, b'G']),
+                    _ => {}
+                }
+            }
+        }
+        let read = sequence::encode(&read);
+
+        let result = MapperBuilder::new(&index)
+            .preset(Preset::LongRead)
+            .build()
+            .map(&read);
+        assert_eq!(result.outcome, Outcome::Mapped);
+        assert!(result.supplementary.is_empty());
+        let mapping = &result.mappings[0];
+        assert_eq!(mapping.pos, 200);
+        assert_eq!(mapping.cigar.query_len(), read.len());
+        assert_eq!(mapping.cigar.ref_len(), 1900);
+        let ops = mapping.cigar.ops();
+        assert!(ops.contains(&(CigarOp::RefSkip, 300)));
+        assert!(ops.iter().any(|(op, _)| *op == CigarOp::Ins));
+        assert!(ops.iter().any(|(op, _)| *op == CigarOp::Del));
+    }
+
+    #[test]
+    fn long_read_is_split_at_large_insertion() {
+        let genome = random_seq(3000, 1);
+        let index = build_index(&[&genome]);
+
+        let mut read = genome[200..900].to_vec();
+        read.extend(random_seq(150, 2));
+        read.extend(&genome[900..1600]);
+        let read = sequence::encode(&read);
+
+        let mut builder = MapperBuilder::new(&index);
+        builder.preset(Preset::LongRead);
+        let result = builder.build().map(&read);
+        assert_eq!(result.supplementary.len(), 1);
+        let mut parts: Vec<_> = result.mappings[..1]
+            .iter()
+            .chain(&result.supplementary)
+            .map(|mapping| (mapping.pos, aligned_range(&mapping.cigar)))
+            .collect();
+        parts.sort_by_key(|(pos, _)| *pos);
+        assert_eq!(parts, vec![(200, 0..700), (900, 850..1550)]);
+
+        // Short reads are not split
+        let result = builder.long_reads(false).build().map(&read);
+        assert!(result.supplementary.is_empty());
+    }
+
+    #[test]
+    fn chimeric_read_has_supplementary_alignment() {
+        let other: &[u8] = b"TCGGATACCTTAGCAGTCAACGGTTCAGACCATTGGACTCGATAAGCCTGGTACTAGC";
+        let index = build_index(&[REFERENCE, other]);
+        let mut builder = MapperBuilder::new(&index);
+        builder.seed_min_len(12).detect_chimeras(true);
+
+        let mut read = REFERENCE[5..35].to_vec();
+        read.extend(&other[20..50]);
+        let read = sequence::encode(&read);
+
+        let result = builder.build().map(&read);
+        assert_eq!(result.supplementary.len(), 1);
+        let mut parts: Vec<_> = result.mappings[..1]
+            .iter()
+            .chain(&result.supplementary)
+            .map(|mapping| (mapping.seq_id.0, mapping.pos, mapping.cigar.to_string()))
+            .collect();
+        parts.sort();
+        assert_eq!(
+            parts,
+            vec![(0, 5, "30M30S".to_owned()), (1, 20, "30S30M".to_owned())]
+        );
+    }
+
+    #[test]
+    fn back_spliced_read_has_supplementary_alignment() {
+        let index = build_index(&[REFERENCE]);
+        let mut builder = MapperBuilder::new(&index);
+        builder.seed_min_len(12).detect_chimeras(true);
+
+        let mut read = REFERENCE[35..60].to_vec();
+        read.extend(&REFERENCE[6..31]);
+        let read = sequence::encode(&read);
+
+        let result = builder.build().map(&read);
+        assert!(result.supplementary.is_empty());
+
+        let result = builder.detect_back_splices(true).build().map(&read);
+        assert_eq!(result.supplementary.len(), 1);
+        let mut parts: Vec<_> = result.mappings[..1]
+            .iter()
+            .chain(&result.supplementary)
+            .map(|mapping| (mapping.pos, mapping.cigar.to_string()))
+            .collect();
+        parts.sort();
+        assert_eq!(
+            parts,
+            vec![(6, "25S25M".to_owned()), (35, "25M25S".to_owned())]
+        );
+    }
+
+    #[test]
+    fn strand_evidence_infers_library_type() {
+        let index = build_index(&[REFERENCE]);
+        let mapper = MapperBuilder::new(&index).seed_min_len(12).build();
+
+        let mut evidence = StrandEvidence::default();
+        for start in [0, 10, 20] {
+            let read = sequence::encode(&REFERENCE[start..start + 30]);
+            evidence.add(&mapper.map(&sequence::reverse_complement(&read)), true);
+        }
+        assert_eq!(evidence.infer(), Strandedness::Reverse);
+
+        for start in [5, 15, 25] {
+            let read = sequence::encode(&REFERENCE[start..start + 30]);
+            evidence.add(&mapper.map(&read), true);
+        }
+        assert_eq!(evidence.infer(), Strandedness::Unstranded);
+    }
+
+    #[test]
+    fn read2_strand_is_opposite_read1() {
+        let index = build_index(&[REFERENCE]);
+        let mut builder = MapperBuilder::new(&index);
+        builder.seed_min_len(12);
+        let mapper = builder.library_type("ISR".parse().unwrap()).build();
+
+        let read = sequence::encode(&REFERENCE[10..40]);
+        assert_eq!(mapper.map_mate_batch(&[&read], true)[0].mappings.len(), 0);
+        assert_eq!(mapper.map_mate_batch(&[&read], false)[0].mappings.len(), 1);
+    }
+
+    #[test]
+    fn reads_best_matching_decoys_are_discarded() {
+        // The decoy shares the first 40 bases of the reference
+        let mut decoy = REFERENCE[..40].to_vec();
+        decoy.extend(b"TTGACCATGACTTGCAGTCA");
+        let mut fasta = format!(
+            ">target\n{}\n>decoy\n",
+            std::str::from_utf8(REFERENCE).unwrap()
+        );
+        fasta.push_str(std::str::from_utf8(&decoy).unwrap());
+        let index = IndexBuilder::new(std::io::Cursor::new(fasta))
+            .decoys(vec![b"decoy".to_vec()])
+            .build()
+            .unwrap();
+        assert!(index.is_decoy(SequenceId(1)));
+        let mapper = MapperBuilder::new(&index).seed_min_len(12).build();
+
+        let result = mapper.map(&sequence::encode(&decoy[20..60]));
+        assert_eq!(result.outcome, Outcome::Decoy);
+        assert!(result.mappings.is_empty());
+
+        let result = mapper.map(&sequence::encode(&REFERENCE[..40]));
+        assert_eq!(result.outcome, Outcome::Mapped);
+        assert!(result
+            .mappings
+            .iter()
+            .all(|mapping| mapping.seq_id == SequenceId(0)));
+    }
+
+    #[test]
+    fn alternative_alleles_are_lifted_to_reference() {
+        let fasta = format!(">seq0\n{}\n", std::str::from_utf8(REFERENCE).unwrap());
+        let variant = Variant {
+            chrom: b"seq0".to_vec(),
+            pos: 30,
+            ref_allele: b"A".to_vec(),
+            alt_alleles: vec![b"G".to_vec()],
+        };
+        let index = IndexBuilder::new(std::io::Cursor::new(fasta))
+            .variants(vec![variant], 20)
+            .build()
+            .unwrap();
+        assert_eq!(index.num_seqs(), 2);
+        assert_eq!(index.seq_name(SequenceId(1)), b"seq0:31:A>G");
+        assert_eq!(
+            index.lift(SequenceId(1)),
+            Some(Lift {
+                seq_id: SequenceId(0),
+                start: 10
+            })
+        );
+        let mapper = MapperBuilder::new(&index).seed_min_len(12).build();
+
+        let reference_read = sequence::encode(&REFERENCE[15..45]);
+        let expected = mapper.map(&reference_read);
+
+        let mut read = reference_read.clone();
+        read[15] = sequence::encode(b"G")[0];
+        let result = mapper.map(&read);
+        assert_eq!(result.mappings.len(), 1);
+        let mapping = &result.mappings[0];
+        assert_eq!(mapping.seq_id, SequenceId(0));
+        assert_eq!(mapping.pos, 15);
+        assert_eq!(mapping.score, expected.mappings[0].score);
+        assert_eq!(mapping.edit_distance, 1);
+    }
+
+    #[test]
+    fn nearby_alternative_alleles_are_combined() {
+        let fasta = format!(">seq0\n{}\n", std::str::from_utf8(REFERENCE).unwrap());
+        let variants = vec![
+            Variant {
+                chrom: b"seq0".to_vec(),
+                pos: 30,
+                ref_allele: b"A".to_vec(),
+                alt_alleles: vec![b"G".to_vec()],
+            },
+            Variant {
+                chrom: b"seq0".to_vec(),
+                pos: 40,
+                ref_allele: b"G".to_vec(),
+                alt_alleles: vec![b"C".to_vec(), b"GA".to_vec()],
+            },
+        ];
+        let index = IndexBuilder::new(std::io::Cursor::new(fasta))
+            .variants(variants, 20)
+            .build()
+            .unwrap();
+        let names: Vec<_> = (1..index.num_seqs())
+            .map(|i| index.seq_name(SequenceId(i)))
+            .collect();
+        assert_eq!(
+            names,
+            [&b"seq0:31:A>G"[..], b"seq0:41:G>C", b"seq0:31:A>G:41:G>C"]
+        );
+        let mut haplotype = REFERENCE[10..60].to_vec();
+        haplotype[20] = b'G';
+        haplotype[30] = b'C';
+        assert_eq!(index.seq(SequenceId(3)), &sequence::encode(&haplotype)[..]);
+        assert_eq!(index.lift(SequenceId(3)).unwrap().start, 10);
+
+        let mapper = MapperBuilder::new(&index).seed_min_len(12).build();
+        let result = mapper.map(&sequence::encode(&haplotype[5..45]));
+        assert_eq!(result.mappings.len(), 1);
+        assert_eq!(result.mappings[0].seq_id, SequenceId(0));
+        assert_eq!(result.mappings[0].pos, 15);
+        assert_eq!(result.mappings[0].edit_distance, 2);
+    }
+
+    #[test]
+    fn alternative_alleles_do_not_make_seeds_repetitive() {
+        let fasta = format!(">seq0\n{}\n", std::str::from_utf8(REFERENCE).unwrap());
+        let variants: Vec<_> = [20, 24, 28, 32]
+            .iter()
+            .map(|&pos| Variant {
+                chrom: b"seq0".to_vec(),
+                pos,
+                ref_allele: vec![REFERENCE[pos]],
+                alt_alleles: vec![if REFERENCE[pos] == b'A' { b"C" } else { b"A" }.to_vec()],
+            })
+            .collect();
+        let index = IndexBuilder::new(std::io::Cursor::new(fasta))
+            .variants(variants, 20)
+            .build()
+            .unwrap();
+        // Every combination of the alleles but the reference one
+        assert_eq!(index.num_auxiliary(), 15);
+        assert_eq!(index.max_copies(), 16);
+
+        // The read is on the reference and on all haplotypes, 16 hits at one position
+        let mapper = MapperBuilder::new(&index)
+            .seed_min_len(12)
+            .seed_max_hits(2)
+            .build();
+        let result = mapper.map(&sequence::encode(&REFERENCE[..20]));
+        assert_eq!(result.outcome, Outcome::Mapped);
+        assert_eq!(result.seed_stats.num_over_cap, 0);
+        assert_eq!(result.mappings[0].seq_id, SequenceId(0));
+        assert_eq!(result.mappings[0].pos, 0);
+    }
+
+    #[test]
+    fn sharded_mapping_matches_whole_index() {
+        let mut paralog = REFERENCE[..40].to_vec();
+        paralog.extend(b"TTGACCATGACTTGCAGTCA");
+        let other = sequence::reverse_complement(&REFERENCE[20..]);
+        let seqs: [&[u8]; 3] = [&other, REFERENCE, &paralog];
+
+        let mut fasta = Vec::new();
+        for (i, seq) in seqs.iter().enumerate() {
+            fasta.extend(format!(">seq{}\n", i).as_bytes());
+            fasta.extend(*seq);
+            fasta.push(b'\n');
+        }
+        let mut shards = Vec::new();
+        let num_shards = IndexBuilder::new(std::io::Cursor::new(fasta))
+            .build_shards(1, |shard| {
+                shards.push(shard);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(num_shards, 3);
+        let sharded = ShardedIndex::new(shards);
+        assert_eq!(sharded.num_seqs(), 3);
+        assert_eq!(sharded.seq_name(SequenceId(2)), b"seq2");
+        assert_eq!(sharded.locate(SequenceId(2)), (2, SequenceId(0)));
+
+        let index = build_index(&seqs);
+        // Seed hits are capped in total, as the first 40 bases are in two shards
+        for (seeding, max_hits) in [
+            (Seeding::Sparse, 10),
+            (Seeding::Sparse, 1),
+            (Seeding::Smem, 1),
+        ] {
+            let mut builder = MapperBuilder::new(&index);
+            builder
+                .seeding(seeding)
+                .seed_min_len(12)
+                .seed_max_hits(max_hits);
+            let mapper = builder.build();
+            let mut builder = MapperBuilder::sharded(&sharded);
+            builder
+                .seeding(seeding)
+                .seed_min_len(12)
+                .seed_max_hits(max_hits);
+            let sharded_mapper = builder.build();
+
+            for read in [&REFERENCE[..40], &REFERENCE[20..], &paralog[30..]] {
+                let read = sequence::encode(read);
+                let expected = mapper.map(&read);
+                let result = sharded_mapper.map(&read);
+                assert_eq!(result.outcome, expected.outcome);
+                assert_eq!(
+                    result.seed_stats.num_over_cap,
+                    expected.seed_stats.num_over_cap
+                );
+
+                let hits = |result: &MapResult| {
+                    let mut hits: Vec<_> = result
+                        .mappings
+                        .iter()
+                        .map(|mapping| (mapping.seq_id, mapping.pos, mapping.strand, mapping.score))
+                        .collect();
+                    hits.sort();
+                    hits
+                };
+                assert_eq!(hits(&result), hits(&expected));
+            }
+        }
+    }
+
+    #[test]
+    fn library_type_codes() {
+        let library_type: LibraryType = "ISR".parse().unwrap();
+        assert_eq!(library_type.orientation, Some(MateOrientation::Inward));
+        assert_eq!(library_type.read_strand(true), Some(Strand::Reverse));
+        assert_eq!(library_type.read_strand(false), Some(Strand::Forward));
+
+        let library_type: LibraryType = "MSF".parse().unwrap();
+        assert_eq!(library_type.read_strand(false), Some(Strand::Forward));
+
+        for code in ["U", "SF", "IU", "OSR", "A", "IA", "MA"] {
+            assert_eq!(code.parse::<LibraryType>().unwrap().to_string(), code);
+        }
+        assert_eq!(
+            "fr-firststrand".parse::<LibraryType>().unwrap().to_string(),
+            "ISR"
+        );
+        assert!("ISX".parse::<LibraryType>().is_err());
+        assert!("é".parse::<LibraryType>().is_err());
+        assert!("".parse::<LibraryType>().is_err());
+    }
+
+    #[test]
+    fn pair_concordance_honors_library_type() {
+        let index = build_index(&[REFERENCE]);
+        let read1 = sequence::encode(&REFERENCE[..25]);
+        let read2 = sequence::reverse_complement(&sequence::encode(&REFERENCE[30..55]));
+
+        let is_concordant = |code: &str| {
+            MapperBuilder::new(&index)
+                .seed_min_len(12)
+                .library_type(code.parse().unwrap())
+                .build()
+                .map_pair(&read1, &read2)
+                .is_concordant
+        };
+        assert!(is_concordant("IU"));
+        assert!(is_concordant("ISF"));
+        assert!(!is_concordant("ISR"));
+        assert!(!is_concordant("OU"));
+        assert!(!is_concordant("MU"));
+    }
+
+    #[test]
+    fn minimizers_are_window_minima() {
+        let seq = sequence::encode(REFERENCE);
+        let (k, w) = (5, 4);
+        let hash_func = HashFunc::XxHash;
+
+        let got = minimizers(&seq, k, w, hash_func);
+
+        let hashes: Vec<_> = seq.windows(k).map(|x| hash_func.hash(x)).collect();
+        let mut expected: Vec<usize> = Vec::new();
+        for start in 0..=(hashes.len() - w) {
+            let pos = (start..start + w).min_by_key(|i| hashes[*i]).unwrap();
+            if expected.last() != Some(&pos) {
+                expected.push(pos);
+            }
+        }
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn minimizers_are_shared_by_overlapping_reads() {
+        let seq = sequence::encode(REFERENCE);
+        let (k, w) = (8, 5);
+
+        let offset = 13;
+        let all = minimizers(&seq, k, w, HashFunc::XxHash);
+        let shifted = minimizers(&seq[offset..], k, w, HashFunc::XxHash);
+        for pos in all.iter().filter(|pos| **pos >= offset + w) {
+            assert!(shifted.contains(&(pos - offset)));
         }
     }
 }
@@ -0,0 +1,699 @@
+use super::Anchor;
+use crate::sequence;
+use std::{fmt, ops::Range};
+
+// Reference gaps at least this much longer than the query gap are reported as introns
+const MIN_INTRON_LEN: usize = 50;
+
+// Most cells of the band of a dynamic programming alignment, each of which keeps
+// a byte of traceback. Larger gaps and ends are aligned without it
+const MAX_DP_CELLS: usize = 1 << 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentMode {
+    /// The whole read is aligned
+    EndToEnd,
+    /// Read ends that do not align well are soft-clipped
+    Local,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CigarOp {
+    Match,
+    Ins,
+    Del,
+    RefSkip,
+    SoftClip,
+}
+
+impl CigarOp {
+    pub fn as_char(self) -> char {
+        match self {
+            Self::Match => 'M',
+            Self::Ins => 'I',
+            Self::Del => 'D',
+            Self::RefSkip => 'N',
+            Self::SoftClip => 'S',
+        }
+    }
+
+    pub fn consumes_query(self) -> bool {
+        matches!(self, Self::Match | Self::Ins | Self::SoftClip)
+    }
+
+    pub fn consumes_ref(self) -> bool {
+        matches!(self, Self::Match | Self::Del | Self::RefSkip)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cigar(Vec<(CigarOp, usize)>);
+
+impl Cigar {
+    pub fn ops(&self) -> &[(CigarOp, usize)] {
+        &self.0
+    }
+
+    pub fn push(&mut self, op: CigarOp, len: usize) {
+        if len == 0 {
+            return;
+        }
+        if let Some((last_op, last_len)) = self.0.last_mut() {
+            if *last_op == op {
+                *last_len += len;
+                return;
+            }
+        }
+        self.0.push((op, len));
+    }
+
+    fn append(&mut self, other: &Cigar) {
+        for (op, len) in &other.0 {
+            self.push(*op, *len);
+        }
+    }
+
+    pub fn query_len(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|(op, _)| op.consumes_query())
+            .map(|(_, len)| len)
+            .sum()
+    }
+
+    pub fn ref_len(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|(op, _)| op.consumes_ref())
+            .map(|(_, len)| len)
+            .sum()
+    }
+}
+
+impl fmt::Display for Cigar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "*");
+        }
+        for (op, len) in &self.0 {
+            write!(f, "{}{}", len, op.as_char())?;
+        }
+        Ok(())
+    }
+}
+
+/// Scores of alignment operations. Penalties are positive and subtracted from the score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoringScheme {
+    pub match_score: i32,
+    pub mismatch_penalty: i32,
+    /// A gap of length `n` costs `gap_open + n * gap_extend`
+    pub gap_open: i32,
+    pub gap_extend: i32,
+    /// Penalty of aligning an ambiguous base. `None` scores it as a mismatch.
+    pub n_penalty: Option<i32>,
+    /// Penalty of an intron
+    pub splice_penalty: i32,
+}
+
+impl Default for ScoringScheme {
+    fn default() -> Self {
+        Self {
+            match_score: 2,
+            mismatch_penalty: 4,
+            gap_open: 4,
+            gap_extend: 2,
+            n_penalty: None,
+            splice_penalty: 0,
+        }
+    }
+}
+
+impl ScoringScheme {
+    fn substitution(&self, x: u8, y: u8) -> i32 {
+        match self.n_penalty {
+            Some(penalty) if x == sequence::DUMMY_CODE || y == sequence::DUMMY_CODE => -penalty,
+            _ if x == y && x != sequence::DUMMY_CODE => self.match_score,
+            _ => -self.mismatch_penalty,
+        }
+    }
+
+    fn ungapped(&self, query: &[u8], reference: &[u8]) -> i32 {
+        query
+            .iter()
+            .zip(reference)
+            .map(|(x, y)| self.substitution(*x, *y))
+            .sum()
+    }
+
+    pub(crate) fn gap(&self, len: usize) -> i32 {
+        if len == 0 {
+            0
+        } else {
+            -(self.gap_open + self.gap_extend * len as i32)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct AlignParams {
+    pub scoring: ScoringScheme,
+    pub mode: AlignmentMode,
+    pub xdrop: i32,
+    pub zdrop: i32,
+    /// Whether reads are noisy long reads, whose gaps between anchors are aligned
+    /// with indels even where the read and the reference gaps have the same length
+    pub long_reads: bool,
+    /// Most diagonals that a gapped alignment strays from those of its ends
+    pub bandwidth: usize,
+}
+
+pub(crate) struct Alignment {
+    pub ref_start: usize,
+    pub cigar: Cigar,
+    pub score: i32,
+}
+
+/// Aligns `query` to `text[ref_range]` through colinear `anchors` sorted by position.
+pub(crate) fn align(
+    text: &[u8],
+    ref_range: Range<usize>,
+    query: &[u8],
+    anchors: &[Anchor],
+    params: &AlignParams,
+) -> Alignment {
+    align_parts(text, ref_range, query, anchors, params).swap_remove(0)
+}
+
+/// Aligns `query` as [`align`] does and returns the alignments of all the parts
+/// of the chain, best first. In local mode, gaps scoring worse than the Z-drop
+/// threshold split the chain, and the ends of each part are extended at most
+/// up to the neighbouring parts.
+pub(crate) fn align_parts(
+    text: &[u8],
+    ref_range: Range<usize>,
+    query: &[u8],
+    anchors: &[Anchor],
+    params: &AlignParams,
+) -> Vec<Alignment> {
+    let scoring = &params.scoring;
+
+    // Trim anchors so that consecutive blocks do not overlap
+    let mut blocks: Vec<(usize, usize, usize)> = Vec::new();
+    for anchor in anchors {
+        let (mut query_pos, mut ref_pos, mut len) = (anchor.query_pos, anchor.ref_pos, anchor.len);
+        if let Some((prev_query_pos, prev_ref_pos, prev_len)) = blocks.last() {
+            let shift = (prev_query_pos + prev_len)
+                .saturating_sub(query_pos)
+                .max((prev_ref_pos + prev_len).saturating_sub(ref_pos));
+            if shift >= len {
+                continue;
+            }
+            query_pos += shift;
+            ref_pos += shift;
+            len -= shift;
+        }
+        blocks.push((query_pos, ref_pos, len));
+    }
+
+    let mut block_scores = Vec::with_capacity(blocks.len());
+    for (query_pos, ref_pos, len) in &blocks {
+        block_scores
+            .push(scoring.ungapped(&query[*query_pos..][..*len], &text[*ref_pos..][..*len]));
+    }
+    let mut gaps = Vec::with_capacity(blocks.len().saturating_sub(1));
+    for pair in blocks.windows(2) {
+        let (query_pos, ref_pos, len) = pair[0];
+        let (next_query_pos, next_ref_pos, _) = pair[1];
+        gaps.push(fill_gap(
+            &query[query_pos + len..next_query_pos],
+            &text[ref_pos + len..next_ref_pos],
+            params,
+        ));
+    }
+
+    // Parts as ranges of blocks
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (i, (_, gap_score)) in gaps.iter().enumerate() {
+        if params.mode == AlignmentMode::Local && *gap_score < -params.zdrop {
+            parts.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    parts.push(start..blocks.len());
+
+    let mut alignments = Vec::with_capacity(parts.len());
+    for (k, part) in parts.iter().enumerate() {
+        let mut core = Cigar::default();
+        let mut score = 0;
+        for i in part.clone() {
+            if i > part.start {
+                let (gap_cigar, gap_score) = &gaps[i - 1];
+                core.append(gap_cigar);
+                score += gap_score;
+            }
+            core.push(CigarOp::Match, blocks[i].2);
+            score += block_scores[i];
+        }
+
+        let (left_query_bound, left_ref_bound) = match k.checked_sub(1) {
+            Some(prev) => {
+                let (query_pos, ref_pos, len) = blocks[parts[prev].end - 1];
+                (query_pos + len, ref_pos + len)
+            }
+            None => (0, ref_range.start),
+        };
+        let (right_query_bound, right_ref_bound) = match parts.get(k + 1) {
+            Some(next) => {
+                let (query_pos, ref_pos, _) = blocks[next.start];
+                (query_pos, ref_pos)
+            }
+            None => (query.len(), ref_range.end),
+        };
+
+        // Ends are extended over a reference window long enough for the query
+        // to align with some deletions
+        let (query_begin, ref_begin, _) = blocks[part.start];
+        let left_ref_begin = ref_begin
+            .saturating_sub(max_extension_ref_len(query_begin - left_query_bound))
+            .max(left_ref_bound);
+        let left_query: Vec<_> = query[left_query_bound..query_begin]
+            .iter()
+            .rev()
+            .copied()
+            .collect();
+        let left_ref: Vec<_> = text[left_ref_begin..ref_begin]
+            .iter()
+            .rev()
+            .copied()
+            .collect();
+        let (left_cigar, left_score) = extend(&left_query, &left_ref, params);
+
+        let (query_pos, ref_pos, len) = blocks[part.end - 1];
+        let (query_end, ref_end) = (query_pos + len, ref_pos + len);
+        let right_ref_end =
+            (ref_end + max_extension_ref_len(right_query_bound - query_end)).min(right_ref_bound);
+        let (right_cigar, right_score) = extend(
+            &query[query_end..right_query_bound],
+            &text[ref_end..right_ref_end],
+            params,
+        );
+
+        let mut cigar = Cigar::default();
+        cigar.push(CigarOp::SoftClip, query_begin - left_cigar.query_len());
+        for &(op, len) in left_cigar.ops().iter().rev() {
+            cigar.push(op, len);
+        }
+        cigar.append(&core);
+        cigar.append(&right_cigar);
+        cigar.push(
+            CigarOp::SoftClip,
+            query.len() - query_end - right_cigar.query_len(),
+        );
+        debug_assert_eq!(cigar.query_len(), query.len());
+
+        alignments.push(Alignment {
+            ref_start: ref_begin - left_cigar.ref_len(),
+            cigar,
+            score: score + left_score + right_score,
+        });
+    }
+
+    alignments.sort_by_key(|alignment| std::cmp::Reverse(alignment.score));
+    alignments
+}
+
+fn max_extension_ref_len(query_len: usize) -> usize {
+    query_len + query_len / 2 + 10
+}
+
+/// Returns the number of mismatched, inserted and deleted bases of an alignment.
+pub(crate) fn edit_distance(text: &[u8], ref_start: usize, query: &[u8], cigar: &Cigar) -> usize {
+    let (mut query_pos, mut ref_pos) = (0, ref_start);
+    let mut dist = 0;
+    for &(op, len) in cigar.ops() {
+        match op {
+            CigarOp::Match => {
+                dist += query[query_pos..][..len]
+                    .iter()
+                    .zip(&text[ref_pos..][..len])
+                    .filter(|(x, y)| x != y || **x == sequence::DUMMY_CODE)
+                    .count()
+            }
+            CigarOp::Ins | CigarOp::Del => dist += len,
+            CigarOp::RefSkip | CigarOp::SoftClip => {}
+        }
+        if op.consumes_query() {
+            query_pos += len;
+        }
+        if op.consumes_ref() {
+            ref_pos += len;
+        }
+    }
+    dist
+}
+
+/// Extends an alignment from the start of `query` and `reference`.
+///
+/// In end-to-end mode, the whole query is aligned without gaps. In local mode,
+/// the best scoring prefixes are aligned with gaps, or without gaps up to where
+/// the score drops more than X-drop below the best score seen if they are too long.
+fn extend(query: &[u8], reference: &[u8], params: &AlignParams) -> (Cigar, i32) {
+    let max_len = query.len().min(reference.len());
+    let mut cigar = Cigar::default();
+    match params.mode {
+        AlignmentMode::EndToEnd => {
+            cigar.push(CigarOp::Match, max_len);
+            let score = params
+                .scoring
+                .ungapped(&query[..max_len], &reference[..max_len]);
+            (cigar, score)
+        }
+        AlignmentMode::Local => {
+            if let Some(alignment) =
+                dp_align(query, reference, &params.scoring, params.bandwidth, true)
+            {
+                return alignment;
+            }
+            let mut best = (0, 0);
+            let mut score = 0;
+            for i in 0..max_len {
+                score += params.scoring.substitution(query[i], reference[i]);
+                if score > best.1 {
+                    best = (i + 1, score);
+                } else if score < best.1 - params.xdrop {
+                    break;
+                }
+            }
+            cigar.push(CigarOp::Match, best.0);
+            (cigar, best.1)
+        }
+    }
+}
+
+fn fill_gap(query: &[u8], reference: &[u8], params: &AlignParams) -> (Cigar, i32) {
+    let scoring = &params.scoring;
+    let mut cigar = Cigar::default();
+    if query.len() == reference.len() {
+        // In long reads, several mismatches may come from balanced insertions
+        // and deletions
+        let num_mismatches = query.iter().zip(reference).filter(|(x, y)| x != y).count();
+        if params.long_reads && num_mismatches > 1 {
+            if let Some(alignment) = global_align(query, reference, scoring, params.bandwidth) {
+                return alignment;
+            }
+        }
+        cigar.push(CigarOp::Match, query.len());
+        return (cigar, scoring.ungapped(query, reference));
+    }
+
+    let len_diff = query.len().abs_diff(reference.len());
+    if len_diff < MIN_INTRON_LEN {
+        if let Some(alignment) = global_align(query, reference, scoring, params.bandwidth) {
+            return alignment;
+        }
+    }
+    split_align(query, reference, scoring)
+}
+
+/// Aligns `query` to `reference` with a single gap, placed where the flanking
+/// ungapped alignments score best.
+fn split_align(query: &[u8], reference: &[u8], scoring: &ScoringScheme) -> (Cigar, i32) {
+    let common_len = query.len().min(reference.len());
+    let (gap_op, gap_len, gap_score) = if query.len() > reference.len() {
+        let len = query.len() - reference.len();
+        (CigarOp::Ins, len, scoring.gap(len))
+    } else {
+        let len = reference.len() - query.len();
+        if len >= MIN_INTRON_LEN {
+            (CigarOp::RefSkip, len, -scoring.splice_penalty)
+        } else {
+            (CigarOp::Del, len, scoring.gap(len))
+        }
+    };
+
+    let query_offset = query.len() - common_len;
+    let ref_offset = reference.len() - common_len;
+
+    // score(split) = left[split] + right[split]
+    let mut left = vec![0; common_len + 1];
+    for i in 0..common_len {
+        left[i + 1] = left[i] + scoring.substitution(query[i], reference[i]);
+    }
+    let mut right = vec![0; common_len + 1];
+    for i in (0..common_len).rev() {
+        right[i] =
+            right[i + 1] + scoring.substitution(query[query_offset + i], reference[ref_offset + i]);
+    }
+    let split = (0..=common_len)
+        .max_by_key(|i| (left[*i] + right[*i], std::cmp::Reverse(*i)))
+        .unwrap();
+
+    let mut cigar = Cigar::default();
+    cigar.push(CigarOp::Match, split);
+    cigar.push(gap_op, gap_len);
+    cigar.push(CigarOp::Match, common_len - split);
+    (cigar, left[split] + right[split] + gap_score)
+}
+
+/// Globally aligns `query` to `reference` with affine gap penalties, as [`dp_align`] does.
+fn global_align(
+    query: &[u8],
+    reference: &[u8],
+    scoring: &ScoringScheme,
+    bandwidth: usize,
+) -> Option<(Cigar, i32)> {
+    dp_align(query, reference, scoring, bandwidth, false)
+}
+
+/// Aligns `query` to `reference` with affine gap penalties, on the diagonals at most
+/// `bandwidth` away from those of the start and the end, or returns `None` if that
+/// takes more than `MAX_DP_CELLS` cells.
+///
+/// With `free_end`, only the best scoring prefixes of both are aligned, around
+/// the diagonal of the start.
+fn dp_align(
+    query: &[u8],
+    reference: &[u8],
+    scoring: &ScoringScheme,
+    bandwidth: usize,
+    free_end: bool,
+) -> Option<(Cigar, i32)> {
+    const NEG_INF: i32 = i32::MIN / 2;
+    // Traceback bits of a cell: the operation its best score ends with, if not a
+    // match, and whether the deletion and the insertion ending there open in it
+    const FROM_DEL: u8 = 1;
+    const FROM_INS: u8 = 2;
+    const DEL_OPEN: u8 = 4;
+    const INS_OPEN: u8 = 8;
+
+    let (rows, cols) = (query.len() + 1, reference.len() + 1);
+    let end_diagonal = if free_end {
+        0
+    } else {
+        cols as isize - rows as isize
+    };
+    let lo = (end_diagonal.min(0) - bandwidth as isize).max(1 - rows as isize);
+    let hi = (end_diagonal.max(0) + bandwidth as isize).min(cols as isize - 1);
+    let width = (hi - lo + 1) as usize;
+    if rows * width > MAX_DP_CELLS {
+        return None;
+    }
+    let cell = |i: usize, j: usize| i * width + (j as isize - i as isize - lo) as usize;
+    let open = scoring.gap_open + scoring.gap_extend;
+    let extend = scoring.gap_extend;
+
+    // Best scores of the cells of the last row computed, and of those ending with
+    // an insertion
+    let mut h = vec![NEG_INF; cols];
+    let mut f = vec![NEG_INF; cols];
+    let mut trace = vec![0; rows * width];
+    h[0] = 0;
+    for j in 1..=hi as usize {
+        h[j] = scoring.gap(j);
+        trace[cell(0, j)] = if j == 1 {
+            FROM_DEL | DEL_OPEN
+        } else {
+            FROM_DEL
+        };
+    }
+
+    let mut best = (0, 0, 0);
+    for i in 1..rows {
+        let first = (i as isize + lo).max(0) as usize;
+        let last = (i as isize + hi).min(cols as isize - 1);
+        if last < first as isize {
+            break;
+        }
+        // Cells left of the band score `NEG_INF`, as does the deletion ending there
+        let (mut left, mut e) = (NEG_INF, NEG_INF);
+        let mut diag = h[first.max(1) - 1];
+        if first == 0 {
+            f[0] = scoring.gap(i);
+            h[0] = f[0];
+            left = h[0];
+            trace[cell(i, 0)] = if i == 1 {
+                FROM_INS | INS_OPEN
+            } else {
+                FROM_INS
+            };
+        }
+
+        for j in first.max(1)..=last as usize {
+            let mut bits = 0;
+            e = if left - open >= e - extend {
+                bits |= DEL_OPEN;
+                left - open
+            } else {
+                e - extend
+            };
+            f[j] = if h[j] - open >= f[j] - extend {
+                bits |= INS_OPEN;
+                h[j] - open
+            } else {
+                f[j] - extend
+            };
+
+            // Ties are broken towards matches, then deletions
+            let mut score = diag + scoring.substitution(query[i - 1], reference[j - 1]);
+            if e > score {
+                score = e;
+                bits |= FROM_DEL;
+            }
+            if f[j] > score {
+                score = f[j];
+                bits = bits & !FROM_DEL | FROM_INS;
+            }
+
+            diag = h[j];
+            h[j] = score;
+            left = score;
+            trace[cell(i, j)] = bits;
+            if free_end && score > best.0 {
+                best = (score, i, j);
+            }
+        }
+    }
+
+    let (score, end) = if free_end {
+        (best.0, (best.1, best.2))
+    } else {
+        (h[cols - 1], (rows - 1, cols - 1))
+    };
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = end;
+    let mut state = 0;
+    while i > 0 || j > 0 {
+        let bits = trace[cell(i, j)];
+        match state {
+            FROM_DEL => {
+                ops.push(CigarOp::Del);
+                if bits & DEL_OPEN != 0 {
+                    state = 0;
+                }
+                j -= 1;
+            }
+            FROM_INS => {
+                ops.push(CigarOp::Ins);
+                if bits & INS_OPEN != 0 {
+                    state = 0;
+                }
+                i -= 1;
+            }
+            _ => match bits & (FROM_DEL | FROM_INS) {
+                0 => {
+                    ops.push(CigarOp::Match);
+                    i -= 1;
+                    j -= 1;
+                }
+                from => state = from,
+            },
+        }
+    }
+
+    let mut cigar = Cigar::default();
+    for op in ops.into_iter().rev() {
+        cigar.push(op, 1);
+    }
+    Some((cigar, score))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cigar_string(query: &[u8], reference: &[u8]) -> (String, i32) {
+        let (cigar, score) = global_align(
+            &sequence::encode(query),
+            &sequence::encode(reference),
+            &ScoringScheme::default(),
+            2,
+        )
+        .unwrap();
+        (cigar.to_string(), score)
+    }
+
+    #[test]
+    fn global_alignment() {
+        assert_eq!(
+            cigar_string(b"ACGTACGT", b"ACGTACGT"),
+            ("8M".to_owned(), 16)
+        );
+        assert_eq!(cigar_string(b"ACGTGACGT", b"ACGTACGT").0, "4M1I4M");
+        assert_eq!(cigar_string(b"ACGACGT", b"ACGTACGT").0, "3M1D4M");
+        assert_eq!(cigar_string(b"", b"ACG"), ("3D".to_owned(), -10));
+    }
+
+    #[test]
+    fn banded_alignment() {
+        let scoring = ScoringScheme::default();
+        let reference: Vec<u8> = (0..10000u32).map(|i| (i * 7 % 11 % 4) as u8).collect();
+        let mut query = reference.clone();
+        query.remove(5000);
+        let (cigar, _) = global_align(&query, &reference, &scoring, 10).unwrap();
+        assert_eq!(cigar.to_string(), "5000M1D4999M");
+        assert!(global_align(&query, &reference, &scoring, 100).is_none());
+    }
+
+    #[test]
+    fn gap_with_balanced_indels() {
+        let query = sequence::encode(b"ACGTAGCAGGCTAGCTACGATCG");
+        let reference = sequence::encode(b"ACGTGCAGGCTAGCTACGTATCG");
+        let mut params = AlignParams {
+            scoring: ScoringScheme::default(),
+            mode: AlignmentMode::EndToEnd,
+            xdrop: 20,
+            zdrop: 100,
+            long_reads: false,
+            bandwidth: 50,
+        };
+        assert_eq!(fill_gap(&query, &reference, &params).0.to_string(), "23M");
+
+        params.long_reads = true;
+        let (cigar, _) = fill_gap(&query, &reference, &params);
+        assert_eq!(cigar.to_string(), "4M1I14M1D4M");
+    }
+
+    #[test]
+    fn split_alignment_places_intron() {
+        let exon1 = b"GATTACAGCTTCGAAC";
+        let exon2 = b"CCTAGTTGCAAGCTGT";
+        let intron = [b'T'; 60];
+
+        let query: Vec<_> = exon1.iter().chain(exon2).copied().collect();
+        let reference: Vec<_> = exon1
+            .iter()
+            .chain(intron.iter())
+            .chain(exon2)
+            .copied()
+            .collect();
+        let (cigar, _) = split_align(
+            &sequence::encode(&query),
+            &sequence::encode(&reference),
+            &ScoringScheme::default(),
+        );
+        assert_eq!(cigar.to_string(), "16M60N16M");
+    }
+}
@@ -0,0 +1,107 @@
+use super::{align::ScoringScheme, Anchor};
+
+// Number of preceding anchors considered as predecessors of each anchor
+const MAX_PREDECESSORS: usize = 50;
+
+pub struct Chain {
+    pub anchors: Vec<Anchor>,
+    pub score: i32,
+}
+
+/// Chains colinear anchors on the same reference sequence and strand.
+///
+/// Consecutive anchors in a chain are at most `max_gap` bases apart on the query
+/// and their diagonals differ by at most `bandwidth`, or by at most `max_intron_len`
+/// if the reference gap is the longer one. Chains are scored as their alignments
+/// would be by `scoring`, assuming that anchors are joined by a single gap.
+/// Returns disjoint chains sorted by descending score.
+pub fn chain(
+    anchors: &[Anchor],
+    max_gap: usize,
+    bandwidth: usize,
+    max_intron_len: usize,
+    scoring: &ScoringScheme,
+) -> Vec<Chain> {
+    let mut anchors = anchors.to_vec();
+    anchors.sort_unstable_by_key(|anchor| (anchor.ref_pos, anchor.query_pos));
+    anchors.dedup();
+
+    let mut scores: Vec<i32> = Vec::with_capacity(anchors.len());
+    let mut preds: Vec<Option<usize>> = Vec::with_capacity(anchors.len());
+    for (i, anchor) in anchors.iter().enumerate() {
+        let mut best = (anchor.len as i32 * scoring.match_score, None);
+        for j in (i.saturating_sub(MAX_PREDECESSORS)..i).rev() {
+            let pred = &anchors[j];
+            let ref_dist = anchor.ref_pos - pred.ref_pos;
+            if ref_dist > max_gap + max_intron_len {
+                break;
+            }
+            if pred.query_pos >= anchor.query_pos || ref_dist == 0 {
+                continue;
+            }
+            let query_dist = anchor.query_pos - pred.query_pos;
+            if query_dist > max_gap {
+                continue;
+            }
+
+            let diag_diff = ref_dist.abs_diff(query_dist);
+            let penalty = if diag_diff <= bandwidth {
+                -scoring.gap(diag_diff)
+            } else if ref_dist > query_dist && diag_diff <= max_intron_len {
+                // Introns are also penalized by the logarithm of their length
+                scoring.splice_penalty + (usize::BITS - diag_diff.leading_zeros()) as i32
+            } else {
+                continue;
+            };
+
+            let gain = query_dist.min(ref_dist).min(anchor.len) as i32 * scoring.match_score;
+            let score = scores[j] + gain - penalty;
+            if score > best.0 {
+                best = (score, Some(j));
+            }
+        }
+        scores.push(best.0);
+        preds.push(best.1);
+    }
+
+    let mut ends: Vec<_> = (0..anchors.len()).collect();
+    ends.sort_unstable_by_key(|i| std::cmp::Reverse(scores[*i]));
+
+    let mut used = vec![false; anchors.len()];
+    let mut chains = Vec::new();
+    for end in ends {
+        if used[end] {
+            continue;
+        }
+
+        let mut indices = Vec::new();
+        let mut cur = Some(end);
+        while let Some(i) = cur {
+            if used[i] {
+                break;
+            }
+            used[i] = true;
+            indices.push(i);
+            cur = preds[i];
+        }
+
+        // Score of the part of the chain that does not overlap earlier chains
+        let first = *indices.last().unwrap();
+        let score = match preds[first] {
+            Some(pred) => scores[end] - scores[pred],
+            None => scores[end],
+        };
+
+        chains.push(Chain {
+            anchors: indices
+                .into_iter()
+                .rev()
+                .map(|i| anchors[i].clone())
+                .collect(),
+            score,
+        });
+    }
+
+    chains.sort_by_key(|chain| std::cmp::Reverse(chain.score));
+    chains
+}
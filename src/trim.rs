@@ -0,0 +1,152 @@
+use std::ops::Range;
+
+/// TruSeq, Nextera and small RNA 3' adapters
+pub const COMMON_ADAPTERS: &[&[u8]] = &[b"AGATCGGAAGAGC", b"CTGTCTCTTATACACATCT", b"TGGAATTCTCGG"];
+
+const PHRED_OFFSET: u8 = 33;
+
+pub struct Trimmer {
+    adapters: Vec<Vec<u8>>,
+    min_adapter_overlap: usize,
+    max_error_rate: f64,
+    poly_a_min_len: Option<usize>,
+    quality_cutoff: Option<u8>,
+}
+
+impl Default for Trimmer {
+    fn default() -> Self {
+        Self {
+            adapters: Vec::new(),
+            min_adapter_overlap: 3,
+            max_error_rate: 0.1,
+            poly_a_min_len: None,
+            quality_cutoff: None,
+        }
+    }
+}
+
+impl Trimmer {
+    pub fn adapter(mut self, adapter: &[u8]) -> Self {
+        self.adapters.push(adapter.to_ascii_uppercase());
+        self
+    }
+
+    pub fn min_adapter_overlap(mut self, min_adapter_overlap: usize) -> Self {
+        self.min_adapter_overlap = min_adapter_overlap;
+        self
+    }
+
+    pub fn max_error_rate(mut self, max_error_rate: f64) -> Self {
+        self.max_error_rate = max_error_rate;
+        self
+    }
+
+    /// Trims poly-A tails and poly-T heads of at least `poly_a_min_len` bases.
+    pub fn poly_a_min_len(mut self, poly_a_min_len: usize) -> Self {
+        self.poly_a_min_len = Some(poly_a_min_len);
+        self
+    }
+
+    /// Trims 3' ends with Phred quality below `quality_cutoff` as BWA does.
+    pub fn quality_cutoff(mut self, quality_cutoff: u8) -> Self {
+        self.quality_cutoff = Some(quality_cutoff);
+        self
+    }
+
+    /// Returns the range of `seq` kept after trimming.
+    pub fn trim(&self, seq: &[u8], qual: Option<&[u8]>) -> Range<usize> {
+        let mut end = seq.len();
+
+        if let (Some(cutoff), Some(qual)) = (self.quality_cutoff, qual) {
+            end = quality_trim_end(&qual[..end], cutoff);
+        }
+
+        for adapter in &self.adapters {
+            end = end.min(self.find_adapter(&seq[..end], adapter));
+        }
+
+        let mut start = 0;
+        if let Some(min_len) = self.poly_a_min_len {
+            let tail_len = homopolymer_len(seq[..end].iter().rev(), b'A');
+            if tail_len >= min_len {
+                end -= tail_len;
+            }
+            let head_len = homopolymer_len(seq[..end].iter(), b'T');
+            if head_len >= min_len {
+                start = head_len;
+            }
+        }
+
+        start..end
+    }
+
+    /// Returns the position where `adapter` starts in `seq`, possibly running
+    /// past its 3' end, or the length of `seq` if not found.
+    fn find_adapter(&self, seq: &[u8], adapter: &[u8]) -> usize {
+        for pos in 0..seq.len() {
+            let overlap = (seq.len() - pos).min(adapter.len());
+            if overlap < self.min_adapter_overlap {
+                break;
+            }
+            let max_mismatches = (overlap as f64 * self.max_error_rate) as usize;
+            let mismatches = seq[pos..pos + overlap]
+                .iter()
+                .zip(adapter)
+                .filter(|(x, y)| x.to_ascii_uppercase() != **y)
+                .count();
+            if mismatches <= max_mismatches {
+                return pos;
+            }
+        }
+        seq.len()
+    }
+}
+
+fn quality_trim_end(qual: &[u8], cutoff: u8) -> usize {
+    let mut sum = 0i32;
+    let mut max = 0i32;
+    let mut end = qual.len();
+    for (i, q) in qual.iter().enumerate().rev() {
+        sum += cutoff as i32 - (q.saturating_sub(PHRED_OFFSET)) as i32;
+        if sum < 0 {
+            break;
+        }
+        if sum > max {
+            max = sum;
+            end = i;
+        }
+    }
+    end
+}
+
+fn homopolymer_len<'a, I: Iterator<Item = &'a u8>>(seq: I, base: u8) -> usize {
+    seq.take_while(|x| x.to_ascii_uppercase() == base).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapter() {
+        let trimmer = Trimmer::default().adapter(b"AGATCGGAAGAGC");
+        assert_eq!(trimmer.trim(b"ACGTACGTAGATCGGAAGAGCAAA", None), 0..8);
+        assert_eq!(trimmer.trim(b"ACGTACGTAGATCG", None), 0..8);
+        assert_eq!(trimmer.trim(b"ACGTACGTAGATCCGAAGAGC", None), 0..8);
+        assert_eq!(trimmer.trim(b"ACGTACGTACGTAC", None), 0..14);
+    }
+
+    #[test]
+    fn poly_a() {
+        let trimmer = Trimmer::default().poly_a_min_len(5);
+        assert_eq!(trimmer.trim(b"TTTTTTGCATGCAAAAAAAA", None), 6..12);
+        assert_eq!(trimmer.trim(b"TTTGCATGCAAAA", None), 0..13);
+    }
+
+    #[test]
+    fn quality() {
+        let trimmer = Trimmer::default().quality_cutoff(20);
+        assert_eq!(trimmer.trim(b"ACGTACGT", Some(b"IIIIII##")), 0..6);
+        assert_eq!(trimmer.trim(b"ACGTACGT", Some(b"IIIIIIII")), 0..8);
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::{self, BufRead};
+
+/// A record of a VCF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    pub chrom: Vec<u8>,
+    /// 0-based position of the reference allele
+    pub pos: usize,
+    pub ref_allele: Vec<u8>,
+    pub alt_alleles: Vec<Vec<u8>>,
+}
+
+impl Variant {
+    /// Returns the alternative alleles that replace the reference allele base by base,
+    /// so that positions around the variant are the same on them as on the reference.
+    pub fn substitutions(&self) -> impl Iterator<Item = &Vec<u8>> {
+        let ref_allele = &self.ref_allele;
+        self.alt_alleles.iter().filter(move |alt| {
+            is_bases(ref_allele) && alt.len() == ref_allele.len() && is_bases(alt)
+        })
+    }
+}
+
+/// Reads the variants of a VCF file, ignoring its header and genotypes.
+pub fn read_variants<R: BufRead>(reader: R) -> io::Result<Vec<Variant>> {
+    let mut variants = Vec::new();
+    for (i, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+        let variant = parse_variant(&line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid VCF record on line {}", i + 1),
+            )
+        })?;
+        variants.push(variant);
+    }
+    Ok(variants)
+}
+
+fn parse_variant(line: &[u8]) -> Option<Variant> {
+    let mut fields = line.split(|&c| c == b'\t');
+    let chrom = fields.next()?.to_owned();
+    let pos: usize = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+    fields.next()?;
+    let ref_allele = fields.next()?.to_ascii_uppercase();
+    let alt_alleles = fields
+        .next()?
+        .split(|&c| c == b',')
+        .map(|alt| alt.to_ascii_uppercase())
+        .collect();
+    if pos == 0 {
+        return None;
+    }
+    Some(Variant {
+        chrom,
+        pos: pos - 1,
+        ref_allele,
+        alt_alleles,
+    })
+}
+
+fn is_bases(allele: &[u8]) -> bool {
+    !allele.is_empty()
+        && allele
+            .iter()
+            .all(|base| matches!(base, b'A' | b'C' | b'G' | b'T'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variants() {
+        let vcf = b"##fileformat=VCFv4.2\n\
+            #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+            chr1\t10\trs1\tA\tG,t\t.\tPASS\t.\n\
+            chr1\t20\t.\tAC\tA\t.\tPASS\t.\n\
+            chr2\t5\t.\tG\t<DEL>\t.\tPASS\t.\n\
+            chr2\t8\t.\tA\tG,AT\t.\tPASS\t.\n";
+        let variants = read_variants(&vcf[..]).unwrap();
+        assert_eq!(variants.len(), 4);
+        assert_eq!(
+            variants[0],
+            Variant {
+                chrom: b"chr1".to_vec(),
+                pos: 9,
+                ref_allele: b"A".to_vec(),
+                alt_alleles: vec![b"G".to_vec(), b"T".to_vec()],
+            }
+        );
+        let substitutions =
+            |variant: &Variant| variant.substitutions().cloned().collect::<Vec<_>>();
+        assert_eq!(substitutions(&variants[0]), [b"G", b"T"]);
+        assert!(substitutions(&variants[1]).is_empty());
+        assert!(substitutions(&variants[2]).is_empty());
+        assert_eq!(substitutions(&variants[3]), [b"G"]);
+
+        assert!(read_variants(&b"chr1\tten\t.\tA\tG\n"[..]).is_err());
+    }
+}